@@ -0,0 +1,325 @@
+use std::fmt::Write;
+
+use crate::layout::keys::{KeyGroup, G};
+use crate::layout::layer::Layer;
+use crate::layout::types::{KeyCoords, KeymapEvent, LayerStatus, Orientation};
+use crate::layout::xkb::XkbLayout;
+
+/// Size of the ACK05 body in mm
+const BODY_WIDTH: f32 = 120.0;
+const BODY_HEIGHT: f32 = 65.0;
+
+/// Rotary encoder knob center and radius in mm
+const ROTARY: (f32, f32, f32) = (12.0, 12.0, 11.0);
+
+/// Physical position of a key in mm, relative to the top left corner
+/// of the body in the default orientation (rotary on the left)
+struct KeyShape {
+    index: u8,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+const fn key(index: u8, x: f32, y: f32, width: f32, height: f32) -> KeyShape {
+    KeyShape { index, x, y, width, height }
+}
+
+/// ( CCW=10 ROT CW=11 ) [ 0 ][ 1 ][ 2 ][ 6 ]
+///                      [ 3 ][ 4 ][ 5 ][ _ ]
+///                      [ 7 ][    8   ][ 9 ]
+const KEYS: [KeyShape; 10] = [
+    key(0, 35.5, 2.5, 18.0, 18.0),
+    key(1, 56.5, 2.5, 18.0, 18.0),
+    key(2, 77.5, 2.5, 18.0, 18.0),
+    key(6, 98.5, 2.5, 18.0, 39.0),
+    key(3, 35.5, 23.5, 18.0, 18.0),
+    key(4, 56.5, 23.5, 18.0, 18.0),
+    key(5, 77.5, 23.5, 18.0, 18.0),
+    key(7, 35.5, 44.5, 18.0, 18.0),
+    key(8, 56.5, 44.5, 39.0, 18.0),
+    key(9, 98.5, 44.5, 18.0, 18.0),
+];
+
+/// Rotary encoder pulses are reported as these key indices
+const ROTARY_CCW: u8 = 10;
+const ROTARY_CW: u8 = 11;
+
+/// What a key does when tapped, held or pressed for a long time
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyLabels {
+    pub tap: Option<String>,
+    pub hold: Option<String>,
+    pub long: Option<String>,
+}
+
+impl KeyLabels {
+    pub fn is_empty(&self) -> bool {
+        self.tap.is_none() && self.hold.is_none() && self.long.is_none()
+    }
+}
+
+/// Printable key group, empty groups have no label
+fn group_label(kg: &KeyGroup, keyboard: &XkbLayout) -> Option<String> {
+    let label = keyboard.label(kg);
+    if label.is_empty() {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// Describe a keymap event using tap, hold and long press labels. Keys are
+/// named by the symbols they type on the keyboard layout.
+pub fn describe(ev: &KeymapEvent, keyboard: &XkbLayout) -> KeyLabels {
+    let group_label = |kg: &KeyGroup| group_label(kg, keyboard);
+    let tap = |s: String| KeyLabels { tap: Some(s), ..Default::default() };
+    let hold = |s: String| KeyLabels { hold: Some(s), ..Default::default() };
+
+    match ev {
+        KeymapEvent::No | KeymapEvent::Inh | KeymapEvent::Pass => KeyLabels::default(),
+        KeymapEvent::Kg(kg) => KeyLabels { tap: group_label(kg), ..Default::default() },
+        KeymapEvent::Klong(short, long) => KeyLabels {
+            tap: group_label(short),
+            long: group_label(long),
+            ..Default::default()
+        },
+        KeymapEvent::Khl(kg, l) => KeyLabels {
+            tap: group_label(kg),
+            hold: Some(format!("layer {}", l)),
+            ..Default::default()
+        },
+        KeymapEvent::Khtl(kg, l) => KeyLabels {
+            tap: group_label(kg),
+            hold: Some(format!("tap layer {}", l)),
+            ..Default::default()
+        },
+        KeymapEvent::Kos(kg) => tap(format!("one-shot {}", keyboard.label(kg))),
+        KeymapEvent::Klock(kg) => tap(format!("lock {}", keyboard.label(kg))),
+        KeymapEvent::Ku(c, _) => tap(c.to_string()),
+        KeymapEvent::Mrel(axis, value) => tap(format!("{:?} {:+}", axis, value)),
+        KeymapEvent::Lmove(l) => tap(format!("move to layer {}", l)),
+        KeymapEvent::Lactivate(l) => tap(format!("activate layer {}", l)),
+        KeymapEvent::Ldeactivate(l) => tap(format!("deactivate layer {}", l)),
+        KeymapEvent::Ltoggle(l) => tap(format!("toggle layer {}", l)),
+        KeymapEvent::Ldisable(l) => tap(format!("disable layer {}", l)),
+        KeymapEvent::Lenable(l) => tap(format!("enable layer {}", l)),
+        KeymapEvent::Genable(g) => tap(format!("enable group {}", g)),
+        KeymapEvent::Gdisable(g) => tap(format!("disable group {}", g)),
+        KeymapEvent::Lhold(l) => hold(format!("layer {}", l)),
+        KeymapEvent::Ltap(l) => hold(format!("tap layer {}", l)),
+        KeymapEvent::LhtL(h, t) => KeyLabels {
+            tap: Some(format!("layer {}", t)),
+            hold: Some(format!("layer {}", h)),
+            ..Default::default()
+        },
+        KeymapEvent::LhtK(l, kg) => KeyLabels {
+            tap: group_label(kg),
+            hold: Some(format!("layer {}", l)),
+            ..Default::default()
+        },
+        KeymapEvent::Llock => tap("lock layers".to_string()),
+        KeymapEvent::Oset(Orientation::Normal) => tap("normal orientation".to_string()),
+        KeymapEvent::Oset(Orientation::Rotated) => tap("rotated orientation".to_string()),
+        KeymapEvent::Oflip => tap("flip orientation".to_string()),
+    }
+}
+
+/// Event of a key in a layer, following the inherit chain for inherited keys
+/// the same way the layer switcher does
+fn inherited_event(layers: &[Layer], idx: usize, coords: KeyCoords) -> &KeymapEvent {
+    let mut layer_idx = idx;
+    // Every layer is visited at most once, a cycle inherits nothing
+    for _ in 0..layers.len() {
+        let layer = &layers[layer_idx];
+        match layer.get_key_event(coords) {
+            KeymapEvent::Inh => match layer.inherit.filter(|p| *p < layers.len()) {
+                Some(parent) => layer_idx = parent,
+                None => return &layer.default_action,
+            },
+            ev => return ev,
+        }
+    }
+    &KeymapEvent::Pass
+}
+
+/// Event of a key in a layer. Keys the layer passes through show what the
+/// layers below it do, those are the layers active after a reset that have
+/// a lower priority or position.
+fn key_event(layers: &[Layer], idx: usize, coords: KeyCoords) -> &KeymapEvent {
+    let order = |i: usize| (layers[i].priority, i);
+    let mut below: Vec<usize> = (0..layers.len())
+        .filter(|i| *i == 0 || layers[*i].status_on_reset == LayerStatus::LayerActive)
+        .filter(|i| order(*i) < order(idx))
+        .collect();
+    below.sort_by_key(|i| std::cmp::Reverse(order(*i)));
+
+    std::iter::once(idx)
+        .chain(below)
+        .map(|i| inherited_event(layers, i, coords))
+        .find(|ev| !matches!(ev, KeymapEvent::Pass | KeymapEvent::Inh))
+        .unwrap_or(&KeymapEvent::No)
+}
+
+fn key_labels(layers: &[Layer], idx: usize, index: u8, keyboard: &XkbLayout) -> KeyLabels {
+    describe(key_event(layers, idx, KeyCoords(0, 0, index)), keyboard)
+}
+
+fn key_name(index: u8) -> String {
+    match index {
+        ROTARY_CCW => format!("CCW ({})", index),
+        ROTARY_CW => format!("CW ({})", index),
+        _ => index.to_string(),
+    }
+}
+
+fn held_keys(layer: &Layer, keyboard: &XkbLayout) -> Option<String> {
+    if layer.on_active_keys.is_empty() {
+        return None;
+    }
+
+    let held = layer.on_active_keys.iter().fold(G(), |g, k| g.k(*k));
+    Some(keyboard.label(&held))
+}
+
+/// Render all layers as a Markdown document with a table per layer
+pub fn render_markdown(layers: &[Layer], keyboard: &XkbLayout) -> String {
+    let mut out = String::from("# XP-Pen ACK05 layout\n");
+
+    for (idx, layer) in layers.iter().enumerate() {
+        let _ = write!(out, "\n## Layer {}\n\n", idx);
+        if let Some(held) = held_keys(layer, keyboard) {
+            let _ = write!(out, "Holds `{}` while active.\n\n", held);
+        }
+
+        out.push_str("| Key | Tap | Hold | Long |\n");
+        out.push_str("|-----|-----|------|------|\n");
+
+        let cell = |s: &Option<String>| s.as_ref().map_or(String::new(), |s| format!("`{}`", s));
+        for index in 0..=ROTARY_CW {
+            let labels = key_labels(layers, idx, index, keyboard);
+            if labels.is_empty() {
+                continue;
+            }
+
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {} |",
+                key_name(index),
+                cell(&labels.tap),
+                cell(&labels.hold),
+                cell(&labels.long)
+            );
+        }
+    }
+
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Write a text element, squeezing it when it is wider than `max_width`
+fn svg_text(out: &mut String, x: f32, y: f32, size: f32, max_width: f32, class: &str, text: &str) {
+    // Rough estimate of the width of a sans-serif text
+    let width = text.chars().count() as f32 * size * 0.55;
+    let squeeze = if width > max_width {
+        format!(r#" textLength="{:.1}" lengthAdjust="spacingAndGlyphs""#, max_width)
+    } else {
+        String::new()
+    };
+
+    let _ = writeln!(
+        out,
+        r#"  <text x="{:.1}" y="{:.1}" font-size="{}" class="{}"{}>{}</text>"#,
+        x, y, size, class, squeeze, xml_escape(text)
+    );
+}
+
+/// Render the layer `idx` as a printable SVG in the physical ACK05 size. The rotated
+/// orientation turns the pad by 180 degrees, the labels stay upright and each
+/// physical key shows the logical key it acts as.
+pub fn render_svg(layers: &[Layer], idx: usize, orientation: Orientation, keyboard: &XkbLayout) -> String {
+    let rotated = orientation == Orientation::Rotated;
+
+    // Position of a shape in the selected orientation
+    let place = |x: f32, y: f32, w: f32, h: f32| {
+        if rotated {
+            (BODY_WIDTH - x - w, BODY_HEIGHT - y - h)
+        } else {
+            (x, y)
+        }
+    };
+
+    // Logical key index of a physical key
+    let logical = |index: u8| orientation.logical(KeyCoords(0, 0, index)).2;
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}mm" height="{h}mm" viewBox="0 0 {w} {h}">"#,
+        w = BODY_WIDTH,
+        h = BODY_HEIGHT + 8.0
+    );
+    out.push_str(concat!(
+        "  <style>text { font-family: sans-serif; text-anchor: middle; } ",
+        ".tap { font-weight: bold; } .hold { fill: #1a5fb4; } .long { fill: #a51d2d; } ",
+        ".index { fill: #777; text-anchor: start; }</style>\n"
+    ));
+    let _ = writeln!(
+        out,
+        r##"  <rect x="0" y="0" width="{}" height="{}" rx="3" fill="#b2b2b2"/>"##,
+        BODY_WIDTH, BODY_HEIGHT
+    );
+
+    for shape in KEYS.iter() {
+        let (x, y) = place(shape.x, shape.y, shape.width, shape.height);
+        let index = logical(shape.index);
+        let labels = key_labels(layers, idx, index, keyboard);
+        let cx = x + shape.width / 2.0;
+        let cy = y + shape.height / 2.0;
+
+        let _ = writeln!(
+            out,
+            r##"  <rect x="{:.1}" y="{:.1}" width="{}" height="{}" rx="1.8" fill="#fff" stroke="#333" stroke-width="0.3"/>"##,
+            x, y, shape.width, shape.height
+        );
+        svg_text(&mut out, x + 1.0, y + 3.0, 2.2, shape.width, "index", &index.to_string());
+
+        if let Some(long) = &labels.long {
+            svg_text(&mut out, cx, cy - 4.5, 2.5, shape.width - 2.0, "long", &format!("long: {}", long));
+        }
+        if let Some(tap) = &labels.tap {
+            svg_text(&mut out, cx, cy + 1.0, 3.0, shape.width - 2.0, "tap", tap);
+        }
+        if let Some(hold) = &labels.hold {
+            svg_text(&mut out, cx, cy + 5.5, 2.5, shape.width - 2.0, "hold", &format!("hold: {}", hold));
+        }
+    }
+
+    let (rx, ry, r) = ROTARY;
+    let (rx, ry) = place(rx - r, ry - r, 2.0 * r, 2.0 * r);
+    let (rx, ry) = (rx + r, ry + r);
+    let _ = writeln!(
+        out,
+        r##"  <circle cx="{:.1}" cy="{:.1}" r="{}" fill="#828282"/>"##,
+        rx, ry, r
+    );
+    for (index, dy) in [(ROTARY_CCW, -2.0), (ROTARY_CW, 3.0)] {
+        if let Some(tap) = key_labels(layers, idx, logical(index), keyboard).tap {
+            let name = if index == ROTARY_CCW { "CCW" } else { "CW" };
+            svg_text(&mut out, rx, ry + dy, 2.5, 2.0 * r - 2.0, "tap", &format!("{}: {}", name, tap));
+        }
+    }
+
+    let mut caption = format!("Layer {}", idx);
+    if let Some(held) = held_keys(&layers[idx], keyboard) {
+        let _ = write!(caption, " (holds {})", held);
+    }
+    svg_text(&mut out, BODY_WIDTH / 2.0, BODY_HEIGHT + 6.0, 4.0, BODY_WIDTH, "caption", &caption);
+
+    out.push_str("</svg>\n");
+    out
+}
@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use evdev::Device;
+use log::{info, warn};
+
+/// Is the sysfs directory name the one of a USB device, like "1-2" or
+/// "3-1.4"? Its interfaces add the configuration and interface number
+/// ("1-2:1.0"), root hubs are named "usbN".
+fn is_usb_device_name(name: &str) -> bool {
+    let Some((bus, ports)) = name.split_once('-') else {
+        return false;
+    };
+    !bus.is_empty()
+        && bus.bytes().all(|b| b.is_ascii_digit())
+        && !ports.is_empty()
+        && ports.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+}
+
+/// The USB device a sysfs device path belongs to, for both the hidraw
+/// and the input devices below its interfaces
+pub fn usb_parent(syspath: &Path) -> Option<&Path> {
+    syspath
+        .ancestors()
+        .find(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(is_usb_device_name))
+}
+
+/// Is the input device at the sysfs path another interface of the USB
+/// device `parent`?
+pub fn is_sibling(syspath: &Path, parent: &Path) -> bool {
+    usb_parent(syspath) == Some(parent)
+}
+
+/// Mount point of sysfs
+pub const SYSFS: &str = "/sys";
+
+/// Resolved sysfs path of a device node, like /dev/hidraw3 in the hidraw
+/// class or /dev/input/event5 in the input class
+fn sysfs_device(sysfs: &Path, node: &Path, class: &str) -> Option<PathBuf> {
+    let name = node.file_name()?;
+    fs::canonicalize(sysfs.join("class").join(class).join(name).join("device")).ok()
+}
+
+/// sysfs path of the USB device a hidraw node like /dev/hidraw3 belongs to
+pub fn hidraw_usb_device(sysfs: &Path, hidraw: &str) -> Option<PathBuf> {
+    let syspath = sysfs_device(sysfs, Path::new(hidraw), "hidraw")?;
+    usb_parent(&syspath).map(Path::to_path_buf)
+}
+
+/// The evdev nodes, like /dev/input/event5, of the interfaces of the USB
+/// device `parent`
+pub fn sibling_nodes<I>(sysfs: &Path, parent: &Path, nodes: I) -> Vec<PathBuf>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    nodes
+        .into_iter()
+        .filter(|path| sysfs_device(sysfs, path, "input").is_some_and(|p| is_sibling(&p, parent)))
+        .collect()
+}
+
+/// Exclusive grab (EVIOCGRAB) of the pad's evdev nodes. The kernel's
+/// hid-generic driver exposes the pad interfaces as ordinary keyboards,
+/// their input would reach the desktop next to the driver output.
+/// The grab is released when dropped.
+pub struct SiblingGrab {
+    devices: Vec<(PathBuf, Device)>,
+}
+
+impl SiblingGrab {
+    /// Grab all evdev nodes of the USB device the hidraw node belongs to.
+    /// Other pads and the driver's own nodes (`own`) are left alone.
+    pub fn grab(hidraw: &str, own: &[PathBuf]) -> Self {
+        let sysfs = Path::new(SYSFS);
+        let Some(parent) = hidraw_usb_device(sysfs, hidraw) else {
+            warn!("{} is not a USB device, its keyboard interfaces are not grabbed", hidraw);
+            return Self { devices: Vec::new() };
+        };
+
+        let nodes = evdev::enumerate()
+            .map(|(path, _)| path)
+            .filter(|path| !own.iter().any(|o| o.file_name() == path.file_name()));
+        Self::grab_nodes(sibling_nodes(sysfs, &parent, nodes))
+    }
+
+    /// Grab the given evdev nodes. Nodes that cannot be grabbed are
+    /// reported and left alone.
+    pub fn grab_nodes<I>(nodes: I) -> Self
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let mut devices = Vec::new();
+
+        for path in nodes {
+            let mut device = match Device::open(&path) {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!("Cannot open {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let name = device.name().unwrap_or("").to_string();
+
+            match device.grab() {
+                Ok(()) => {
+                    info!("Grabbed {} ({})", path.display(), name);
+                    devices.push((path, device));
+                }
+                Err(e) => warn!("Cannot grab {} ({}): {}", path.display(), name, e),
+            }
+        }
+
+        Self { devices }
+    }
+
+    /// Paths of the grabbed nodes
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.devices.iter().map(|(path, _)| path)
+    }
+}
+
+impl Drop for SiblingGrab {
+    fn drop(&mut self) {
+        for (path, device) in self.devices.iter_mut() {
+            if let Err(e) = device.ungrab() {
+                warn!("Cannot release {}: {}", path.display(), e);
+            }
+        }
+    }
+}
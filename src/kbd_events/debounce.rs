@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use enumset::{EnumSet, EnumSetType};
+
+use super::HasState;
+use crate::timer::Deadline;
+
+/// Default debounce window of all buttons
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DebounceMode {
+    /// A change is reported immediately, changes within the window after it
+    /// are deferred to its end. No latency, but a single spike of noise is reported.
+    #[default]
+    Eager,
+    /// A change is reported once the button was stable for the whole window.
+    /// Filters noise as well, but delays every change by the window.
+    Deferred,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ButtonState {
+    /// State reported to the change detector
+    stable: bool,
+    /// Last state reported by the device
+    raw: bool,
+    /// Time of the last accepted change (eager) or raw change (deferred)
+    since: Option<Instant>,
+}
+
+/// Filters switch chatter between the device reports and the change detector.
+///
+/// A bounce is counted each time a button returns to its reported state
+/// before its debounce window elapsed, the short glitch is then never seen
+/// by the change detector. Buttons without state (the rotary encoder)
+/// are passed through unchanged.
+pub struct Debouncer<T>
+where
+    T: EnumSetType + Hash,
+{
+    mode: DebounceMode,
+    window: Duration,
+    /// Per button windows overriding the default one
+    windows: HashMap<T, Duration>,
+    buttons: HashMap<T, ButtonState>,
+    /// Stateless buttons of the last report
+    stateless: EnumSet<T>,
+    /// Number of filtered bounces per button
+    bounces: HashMap<T, u64>,
+}
+
+impl<T> Debouncer<T>
+where
+    T: EnumSetType + Hash + HasState,
+{
+    pub fn new(mode: DebounceMode, window: Duration) -> Self {
+        Self {
+            mode,
+            window,
+            windows: HashMap::new(),
+            buttons: HashMap::new(),
+            stateless: EnumSet::empty(),
+            bounces: HashMap::new(),
+        }
+    }
+
+    /// Use a different debounce window for a worn out button
+    pub fn set_window(&mut self, button: T, window: Duration) {
+        self.windows.insert(button, window);
+    }
+
+    pub fn get_window(&self, button: T) -> Duration {
+        self.window_of(&button)
+    }
+
+    /// Number of bounces filtered on a button
+    pub fn bounces(&self, button: T) -> u64 {
+        self.bounces.get(&button).copied().unwrap_or(0)
+    }
+
+    /// Number of bounces filtered on all buttons
+    pub fn total_bounces(&self) -> u64 {
+        self.bounces.values().sum()
+    }
+
+    /// Forget the state of all buttons, the next report is taken as is.
+    /// The windows and the bounce counts are kept.
+    pub fn reset(&mut self) {
+        self.buttons.clear();
+        self.stateless = EnumSet::empty();
+    }
+
+    /// Debounced state of all buttons
+    pub fn state(&self) -> EnumSet<T> {
+        let held = self.buttons.iter().filter(|(_, s)| s.stable).map(|(b, _)| *b);
+        self.stateless | EnumSet::from_iter(held)
+    }
+
+    /// True when a change was not reported yet and `poll` has to be called
+    /// once the debounce window elapses
+    pub fn is_pending(&self) -> bool {
+        self.buttons.values().any(|s| s.raw != s.stable)
+    }
+
+    fn window_of(&self, button: &T) -> Duration {
+        self.windows.get(button).copied().unwrap_or(self.window)
+    }
+
+    /// Process a device report and return the debounced state
+    pub fn update(&mut self, input: EnumSet<T>, t: Instant) -> EnumSet<T> {
+        self.stateless = input.iter().filter(|b| !b.has_state()).collect();
+
+        for button in EnumSet::<T>::all().iter().filter(|b| b.has_state()) {
+            let raw = input.contains(button);
+            let state = self.buttons.entry(button).or_insert(ButtonState {
+                stable: false,
+                raw: false,
+                since: None,
+            });
+            if raw == state.raw {
+                continue;
+            }
+
+            if raw == state.stable {
+                // Back to the reported state before the change was accepted
+                *self.bounces.entry(button).or_insert(0) += 1;
+            }
+
+            state.raw = raw;
+            if self.mode == DebounceMode::Deferred {
+                state.since = Some(t);
+            }
+        }
+
+        self.accept(t);
+        self.state()
+    }
+
+    /// Apply the changes whose debounce window elapsed. Returns the new
+    /// debounced state when it changed since the last update or poll.
+    pub fn poll(&mut self, t: Instant) -> Option<EnumSet<T>> {
+        // Stateless buttons only exist in the report that contains them
+        self.stateless = EnumSet::empty();
+        let before = self.state();
+        self.accept(t);
+
+        let after = self.state();
+        if before != after {
+            Some(after)
+        } else {
+            None
+        }
+    }
+
+    fn accept(&mut self, t: Instant) {
+        for (button, state) in self.buttons.iter_mut() {
+            if state.raw == state.stable {
+                continue;
+            }
+
+            let window = self.windows.get(button).copied().unwrap_or(self.window);
+            let elapsed = state.since.is_none_or(|since| t - since >= window);
+            if elapsed {
+                state.stable = state.raw;
+                if self.mode == DebounceMode::Eager {
+                    state.since = Some(t);
+                }
+            }
+        }
+    }
+}
+
+impl<T> Deadline for Debouncer<T>
+where
+    T: EnumSetType + Hash + HasState,
+{
+    /// The end of the debounce window of the pending changes
+    fn next_deadline(&self) -> Option<Instant> {
+        self.buttons
+            .iter()
+            .filter(|(_, s)| s.raw != s.stable)
+            .filter_map(|(b, s)| s.since.map(|since| since + self.window_of(b)))
+            .min()
+    }
+}
@@ -1,14 +1,21 @@
-use core::time;
+pub mod debounce;
+
 use enumset::{EnumSet, EnumSetType};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use crate::timer::Deadline;
+
+/// Keys held for at least this long are long pressed by default,
+/// the layer switcher uses the same threshold to tell a hold from a tap
+pub const LONG_PRESS: Duration = Duration::from_millis(200);
 
 pub trait HasState {
     fn has_state(self) -> bool;
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum KeyStateChange<T> {
     /// Key was pressed and is held down
     Pressed(T),
@@ -22,14 +29,32 @@ pub enum KeyStateChange<T> {
     LongPress(T),
 }
 
+/// Detects key state changes between consecutive device reports.
+///
+/// Events are delivered in a stable order: all releases of a report come
+/// first, then the presses, clicks and long presses, each group ordered
+/// by the button index. Events of consecutive reports and ticks are
+/// delivered first in, first out. Releasing keys first lets the layer
+/// switcher resolve a hold/tap key before the next key press is seen.
 pub struct ChangeDetector<T>
 where
     T: EnumSetType + Hash,
 {
     /// T -> time of press, short(F)/long(T)
     state: HashMap<T, (Instant, bool)>,
-    /// Computed events that were not yet consumed
-    events: Vec<KeyStateChange<T>>,
+    /// Computed events that were not yet consumed, oldest first
+    events: VecDeque<KeyStateChange<T>>,
+    /// Keys held for at least this long are long pressed
+    long_press: Duration,
+}
+
+impl<T> Default for ChangeDetector<T>
+where
+    T: EnumSetType + Hash + HasState,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> ChangeDetector<T>
@@ -39,18 +64,26 @@ where
     pub fn new() -> Self {
         Self {
             state: HashMap::new(),
-            events: Vec::new(),
+            events: VecDeque::new(),
+            long_press: LONG_PRESS,
         }
     }
 
+    pub fn set_long_press(&mut self, long_press: Duration) {
+        self.long_press = long_press;
+    }
+
+    pub fn get_long_press(&self) -> Duration {
+        self.long_press
+    }
+
     /// Time tick, checks for long presses
     pub fn tick(&mut self, t: Instant) {
-        let keys = Vec::from_iter(self.state.keys().map(|k| *k));
-        for k in keys {
+        for k in self.held() {
             let (press_t, long_p) = self.state.get(&k).unwrap();
             // check press timestamp and send LongPress
-            if t - *press_t > time::Duration::from_millis(200) {
-                self.events.push(KeyStateChange::LongPress(k));
+            if t - *press_t > self.long_press {
+                self.events.push_back(KeyStateChange::LongPress(k));
 
                 if !long_p {
                     // Update the record to indicate long press was already sent
@@ -66,29 +99,29 @@ where
     pub fn analyze(&mut self, input: EnumSet<T>, t: Instant) -> bool {
         let mut new_presses_detected = false;
 
-        // Retrieve released keys
-        for k in self.state.keys() {
-            if !input.contains(*k) && k.has_state() {
-                self.events.push(KeyStateChange::Released(*k))
+        // Retrieve released keys, they go before the presses
+        for k in self.held() - input {
+            if k.has_state() {
+                self.events.push_back(KeyStateChange::Released(k))
             }
         }
 
-        // Retrieve pressed keys
+        // Retrieve pressed keys in the button index order
         for k in input {
             if !self.state.contains_key(&k) || !k.has_state() {
                 if k.has_state() {
-                    self.events.push(KeyStateChange::Pressed(k));
+                    self.events.push_back(KeyStateChange::Pressed(k));
                     new_presses_detected = true;
                 } else {
-                    self.events.push(KeyStateChange::Click(k));
+                    self.events.push_back(KeyStateChange::Click(k));
                 }
             }
 
             if self.state.contains_key(&k) && k.has_state() {
                 let (press_t, long_p) = self.state.get(&k).unwrap();
                 // check press timestamp and send LongPress
-                if t - *press_t > time::Duration::from_millis(200) {
-                    self.events.push(KeyStateChange::LongPress(k));
+                if t - *press_t > self.long_press {
+                    self.events.push_back(KeyStateChange::LongPress(k));
 
                     if !long_p {
                         // Update the record to indicate long press was already sent
@@ -104,16 +137,21 @@ where
 
         // Insert all newly pressed keys with timestamp
         for k in input {
-            if !self.state.contains_key(&k) {
-                self.state.insert(k, (t, false));
-            }
+            self.state.entry(k).or_insert((t, false));
         }
 
-        return new_presses_detected;
+        new_presses_detected
     }
 
+    /// Keys held down since the last report, iterated by the button index
+    fn held(&self) -> EnumSet<T> {
+        self.state.keys().copied().collect()
+    }
+
+    /// Take the oldest event that was not consumed yet
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<KeyStateChange<T>> {
-        self.events.pop()
+        self.events.pop_front()
     }
 
     pub fn has_pressed(&self) -> bool {
@@ -121,6 +159,22 @@ where
     }
 
     pub fn has_short_pressed(&self) -> bool {
-        (&self.state).into_iter().any(|i| !i.1 .1)
+        self.state.iter().any(|i| !i.1 .1)
+    }
+}
+
+impl<T> Deadline for ChangeDetector<T>
+where
+    T: EnumSetType + Hash + HasState,
+{
+    /// The next long press of a key that was not long pressed yet. A long
+    /// press needs more than the threshold, so the deadline is the first
+    /// instant after it.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.state
+            .iter()
+            .filter(|(k, (_, long_p))| k.has_state() && !long_p)
+            .map(|(_, (press_t, _))| *press_t + self.long_press + Duration::from_nanos(1))
+            .min()
     }
 }
@@ -1,4 +1,21 @@
+use std::fmt;
+use std::str::FromStr;
+
 use super::types::KeymapEvent;
+use super::xkb::XkbLayout;
+
+/// Vocabulary of named media and system control actions
+pub const NAMED_ACTIONS: &[(&str, evdev::Key)] = &[
+    ("volume_up", evdev::Key::KEY_VOLUMEUP),
+    ("volume_down", evdev::Key::KEY_VOLUMEDOWN),
+    ("mute", evdev::Key::KEY_MUTE),
+    ("play_pause", evdev::Key::KEY_PLAYPAUSE),
+    ("next_track", evdev::Key::KEY_NEXTSONG),
+    ("previous_track", evdev::Key::KEY_PREVIOUSSONG),
+    ("stop", evdev::Key::KEY_STOPCD),
+    ("brightness_up", evdev::Key::KEY_BRIGHTNESSUP),
+    ("brightness_down", evdev::Key::KEY_BRIGHTNESSDOWN),
+];
 
 #[derive(Clone, Hash, Debug, PartialEq)]
 pub struct KeyGroup {
@@ -46,6 +63,7 @@ impl KeyGroup {
     }
 }
 
+#[allow(non_snake_case)]
 pub fn G() -> KeyGroup {
     KeyGroup {
         sequential: false,
@@ -54,10 +72,235 @@ pub fn G() -> KeyGroup {
     }
 }
 
+#[allow(non_snake_case)]
 pub fn S() -> KeyGroup {
     KeyGroup {
         sequential: true,
         keys: vec![],
         mask: vec![],
     }
-}
\ No newline at end of file
+}
+
+/// Modifier names accepted in shortcut strings. The first name of each
+/// key is used when printing.
+pub const MODIFIERS: &[(&str, evdev::Key)] = &[
+    ("Ctrl", evdev::Key::KEY_LEFTCTRL),
+    ("Control", evdev::Key::KEY_LEFTCTRL),
+    ("Shift", evdev::Key::KEY_LEFTSHIFT),
+    ("Alt", evdev::Key::KEY_LEFTALT),
+    ("AltGr", evdev::Key::KEY_RIGHTALT),
+    ("Super", evdev::Key::KEY_LEFTMETA),
+    ("Meta", evdev::Key::KEY_LEFTMETA),
+    ("Win", evdev::Key::KEY_LEFTMETA),
+];
+
+/// Find a modifier key by its name, ignoring case
+pub fn modifier(name: &str) -> Option<evdev::Key> {
+    MODIFIERS
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, k)| *k)
+}
+
+/// Find a key by a modifier name or its evdev name with or without
+/// the KEY_ prefix ("Esc", "KEY_F12", "BTN_LEFT"), ignoring case
+pub fn key_by_name(name: &str) -> Option<evdev::Key> {
+    if let Some(k) = modifier(name) {
+        return Some(k);
+    }
+
+    let name = name.to_uppercase();
+    evdev::Key::from_str(&name)
+        .or_else(|_| evdev::Key::from_str(&format!("KEY_{}", name)))
+        .ok()
+}
+
+/// Printable name of a key, the inverse of `key_by_name`
+pub fn key_name(key: evdev::Key) -> String {
+    if let Some((name, _)) = MODIFIERS.iter().find(|(_, k)| *k == key) {
+        return name.to_string();
+    }
+
+    let name = format!("{:?}", key);
+    match name.strip_prefix("KEY_") {
+        Some(n) => {
+            let mut chars = n.chars();
+            chars.next().map_or(String::new(), |c| {
+                c.to_string() + &chars.as_str().to_lowercase()
+            })
+        }
+        None => name,
+    }
+}
+
+/// Translates a single key name to the keys producing it, modifiers first
+pub type Resolver<'a> = dyn Fn(&str) -> Option<Vec<evdev::Key>> + 'a;
+
+/// Parse a "+" separated chord of key names
+fn parse_chord(s: &str, resolve: &Resolver<'_>) -> Result<Vec<evdev::Key>, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+
+    // "+" alone is the plus key and "Ctrl++" means Ctrl and the plus key
+    if s == "+" {
+        parts = vec!["+"];
+    } else if s.ends_with("++") {
+        parts.truncate(parts.len() - 2);
+        parts.push("+");
+    }
+
+    let mut keys = Vec::new();
+    for part in parts {
+        if part.is_empty() {
+            return Err(format!("Empty key name in \"{}\"", s));
+        }
+
+        let resolved = resolve(part).ok_or_else(|| format!("Unknown key \"{}\" in \"{}\"", part, s))?;
+        for k in resolved {
+            if !keys.contains(&k) {
+                keys.push(k);
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Parse a shortcut string into a key group. The supported forms are
+/// a chord "Ctrl+Shift+A", a sequence "seq: Esc, Esc" and a masked
+/// group "mask(Shift): E" where the part after the colon is a chord
+/// or a sequence.
+pub fn parse_shortcut(s: &str, resolve: &Resolver<'_>) -> Result<KeyGroup, String> {
+    let s = s.trim();
+
+    if let Some(rest) = s.strip_prefix("mask(") {
+        let (mask, rest) = rest
+            .split_once("):")
+            .ok_or_else(|| format!("Missing \"):\" after the mask in \"{}\"", s))?;
+
+        let mut group = parse_shortcut(rest, resolve)?;
+        for k in parse_chord(mask, resolve)? {
+            group = group.m(k);
+        }
+        return Ok(group);
+    }
+
+    if let Some(rest) = s.strip_prefix("seq:") {
+        let mut group = S();
+        for name in rest.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match resolve(name).as_deref() {
+                Some([k]) => group = group.k(*k),
+                Some(_) => {
+                    return Err(format!(
+                        "\"{}\" needs modifiers and cannot be used in the sequence \"{}\"",
+                        name, s
+                    ))
+                }
+                None => return Err(format!("Unknown key \"{}\" in \"{}\"", name, s)),
+            }
+        }
+        return Ok(group);
+    }
+
+    let mut group = G();
+    for k in parse_chord(s, resolve)? {
+        group = group.k(k);
+    }
+    Ok(group)
+}
+
+/// Parses shortcuts using evdev key names, see `parse_shortcut`
+impl FromStr for KeyGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_shortcut(s, &|name| key_by_name(name).map(|k| vec![k]))
+    }
+}
+
+impl KeyGroup {
+    /// Print the key group using the shortcut syntax, naming each key by `name`
+    pub fn format_with(&self, name: &dyn Fn(evdev::Key) -> String) -> String {
+        let names = |keys: &[evdev::Key]| keys.iter().map(|k| name(*k)).collect::<Vec<_>>();
+
+        let mut out = String::new();
+        if !self.mask.is_empty() {
+            out.push_str(&format!("mask({}): ", names(&self.mask).join("+")));
+        }
+
+        if self.sequential {
+            out.push_str(&format!("seq: {}", names(&self.keys).join(", ")));
+        } else {
+            out.push_str(&names(&self.keys).join("+"));
+        }
+        out
+    }
+}
+
+/// Prints the key group using the shortcut syntax and evdev key names
+impl fmt::Display for KeyGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format_with(&key_name))
+    }
+}
+
+/// Create a key group for a named media or system control action
+/// (see `NAMED_ACTIONS`)
+pub fn action(name: &str) -> Option<KeyGroup> {
+    NAMED_ACTIONS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, k)| G().k(*k))
+}
+
+/// Method used to type characters that have no key on the keyboard
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnicodeMethod {
+    /// IBus / GTK hex entry: Ctrl+Shift+U, hex digits of the code point and Space.
+    /// Holds the key groups of Ctrl+Shift+U, the digits 0-9 and a-f and Space
+    /// on the keyboard layout, see `UnicodeMethod::hex_entry`.
+    HexEntry(KeyGroup, Vec<KeyGroup>, KeyGroup),
+    /// Compose key followed by a user defined sequence of key groups for each character
+    Compose(evdev::Key, Vec<(char, Vec<KeyGroup>)>),
+}
+
+impl UnicodeMethod {
+    /// Hex entry typing the digits by symbol, AZERTY needs Shift for them
+    pub fn hex_entry(keyboard: &XkbLayout) -> Result<Self, String> {
+        let digits = "0123456789abcdef"
+            .chars()
+            .map(|d| keyboard.shortcut(&d.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(UnicodeMethod::HexEntry(keyboard.shortcut("Ctrl+Shift+U")?, digits, keyboard.shortcut("Space")?))
+    }
+
+    /// Translate a character to key groups that are clicked one after another.
+    /// Returns None when the method has no way to type the character.
+    pub fn keygroups(&self, c: char) -> Option<Vec<KeyGroup>> {
+        match self {
+            UnicodeMethod::HexEntry(start, digits, end) => {
+                let mut kgs = vec![start.clone()];
+                for d in format!("{:x}", c as u32).chars() {
+                    kgs.push(digits.get(d.to_digit(16)? as usize)?.clone());
+                }
+                kgs.push(end.clone());
+                Some(kgs)
+            }
+            UnicodeMethod::Compose(compose, sequences) => {
+                let (_, seq) = sequences.iter().find(|(ch, _)| *ch == c)?;
+                let mut kgs = vec![G().k(*compose)];
+                kgs.extend(seq.iter().cloned());
+                Some(kgs)
+            }
+        }
+    }
+}
+
+/// Create a keymap event typing the character `c` using `method`
+pub fn unicode(c: char, method: &UnicodeMethod) -> Option<KeymapEvent> {
+    method.keygroups(c).map(|kgs| KeymapEvent::Ku(c, kgs))
+}
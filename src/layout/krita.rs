@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::keys::{KeyGroup, G};
+use super::xkb::XkbLayout;
+
+/// Prefix of shortcut strings referring to a Krita action
+pub const ACTION_PREFIX: &str = "action:";
+
+/// Default Krita shortcuts of the actions used by the shipped layout,
+/// a small part of the full Krita scheme
+const DEFAULT_SCHEME: &str = include_str!("../../layouts/krita/default.shortcuts");
+
+/// Location of the Krita user shortcut configuration
+pub fn user_config_path() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("kritashortcutsrc"))
+}
+
+/// Krita action shortcuts read from `.shortcuts` scheme files
+/// and `kritashortcutsrc`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KritaShortcuts {
+    /// Action name -> shortcut in the Qt syntax, None when the
+    /// action has no shortcut assigned
+    actions: HashMap<String, Option<String>>,
+}
+
+/// Undo the KConfig escaping of values
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('s') => out.push(' '),
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+impl KritaShortcuts {
+    /// Shortcuts of the default Krita scheme
+    pub fn default_scheme() -> Self {
+        let mut shortcuts = Self::default();
+        shortcuts.merge_str(DEFAULT_SCHEME).expect("The default Krita scheme is valid");
+        shortcuts
+    }
+
+    /// Add the shortcuts from the [Shortcuts] section of a scheme file content.
+    /// Already known actions are overridden, the same way Krita applies
+    /// the user configuration on top of a scheme.
+    /// Nothing is merged when the section contains a malformed line.
+    pub fn merge_str(&mut self, content: &str) -> Result<(), String> {
+        let mut in_shortcuts = false;
+        let mut merged = Vec::new();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                in_shortcuts = line == "[Shortcuts]";
+                continue;
+            }
+
+            if !in_shortcuts {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(format!("line {}: \"{}\" is not an action=shortcut entry", idx + 1, line));
+            };
+
+            // The first of the alternative shortcuts is the primary one
+            let value = unescape(value);
+            let primary = value.split("; ").next().unwrap_or("").trim();
+            let shortcut = match primary {
+                "" | "none" => None,
+                s => Some(s.to_string()),
+            };
+            merged.push((name.trim().to_string(), shortcut));
+        }
+
+        self.actions.extend(merged);
+        Ok(())
+    }
+
+    /// Add the shortcuts from a `.shortcuts` or `kritashortcutsrc` file
+    pub fn merge_file(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        self.merge_str(&content)
+    }
+
+    /// Get the primary shortcut of an action in the Qt syntax
+    pub fn action(&self, name: &str) -> Option<&str> {
+        self.actions.get(name)?.as_deref()
+    }
+
+    /// Resolve a shortcut string to a key group. "action:<name>" refers to
+    /// a Krita action, anything else is resolved directly by the keyboard layout.
+    /// Actions without a shortcut resolve to an empty group, the pad key then
+    /// does nothing until a shortcut is assigned in Krita again.
+    pub fn shortcut(&self, s: &str, keyboard: &XkbLayout) -> Result<KeyGroup, String> {
+        let s = s.trim();
+        let Some(name) = s.strip_prefix(ACTION_PREFIX) else {
+            return keyboard.shortcut(s);
+        };
+
+        let name = name.trim();
+        match self.actions.get(name) {
+            None => Err(format!("Unknown Krita action \"{}\"", name)),
+            Some(None) => Ok(G()),
+            Some(Some(shortcut)) => keyboard
+                .shortcut(shortcut)
+                .map_err(|e| format!("Krita action \"{}\": {}", name, e)),
+        }
+    }
+}
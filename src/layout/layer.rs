@@ -1,14 +1,26 @@
 use std::time::Duration;
 
-use evdev::Key;
+use evdev::{Key, RelativeAxisType};
 
-use super::types::{KeyCoords, Keymap, KeymapEvent, LayerId, LayerStatus};
+use super::types::{KeyCoords, Keymap, KeymapEvent, LayerGroupId, LayerId, LayerStatus};
 
 #[derive(Clone)]
 pub struct Layer {
     // Should be active on reset?
     pub(crate) status_on_reset: LayerStatus,
 
+    // Layer groups this layer belongs to, all layers in a group
+    // can be enabled or disabled at once
+    pub(crate) groups: Vec<LayerGroupId>,
+
+    // Layers sharing an exclusive group are mutually exclusive,
+    // activating one of them deactivates the others
+    pub(crate) exclusive_group: Option<LayerGroupId>,
+
+    // Key resolution priority, higher priority layers are consulted first.
+    // Layers with equal priority are ordered according to the switcher LayerOrder.
+    pub(crate) priority: i32,
+
     // Where to inherit from when KeymapEvent.Inh is used
     pub(crate) inherit: Option<LayerId>,
 
@@ -18,10 +30,10 @@ pub struct Layer {
     // Are active keys disabled when a key is pressed when the layer is active?
     pub(crate) disable_active_on_press: bool,
 
-    // A layer switch when timer expires
+    // A layer to activate when the timeout expires
     pub(crate) on_timeout_layer: Option<LayerId>,
 
-    // Timeout to setup when layer is entered
+    // The layer is deactivated when it stays active for this long
     pub(crate) timeout: Option<Duration>,
 
     // Keymap definition when this layer is active
@@ -54,6 +66,13 @@ impl Layer {
                         },
                         KeymapEvent::Khtl(k, _) => keys.extend(k.get_used_keys()),
                         KeymapEvent::Khl(k, _) => keys.extend(k.get_used_keys()),
+                        KeymapEvent::Kos(k) => keys.extend(k.get_used_keys()),
+                        KeymapEvent::Klock(k) => keys.extend(k.get_used_keys()),
+                        KeymapEvent::Ku(_, kgs) => {
+                            for k in kgs {
+                                keys.extend(k.get_used_keys());
+                            }
+                        }
 
                         KeymapEvent::LhtK(_, k) => keys.extend(k.get_used_keys()),
                         _ => {}
@@ -61,6 +80,43 @@ impl Layer {
                 }
             }
         }
-        return keys;
+        keys
+    }
+
+    pub fn get_used_axes(&self) -> Vec<RelativeAxisType> {
+        let mut axes = Vec::new();
+        for b in &self.keymap {
+            for r in b {
+                for ev in r {
+                    if let KeymapEvent::Mrel(axis, _) = ev {
+                        axes.push(*axis);
+                    }
+                }
+            }
+        }
+        axes
+    }
+
+    /// Layers this layer refers to in its keymap or via inheritance
+    pub fn get_referenced_layers(&self) -> Vec<LayerId> {
+        let mut layers = Vec::from_iter(self.inherit.into_iter().chain(self.on_timeout_layer));
+        for ev in self.keymap.iter().flatten().flatten().chain([&self.default_action]) {
+            match ev {
+                KeymapEvent::Khl(_, l)
+                | KeymapEvent::Khtl(_, l)
+                | KeymapEvent::Lmove(l)
+                | KeymapEvent::Lactivate(l)
+                | KeymapEvent::Ldeactivate(l)
+                | KeymapEvent::Ltoggle(l)
+                | KeymapEvent::Ldisable(l)
+                | KeymapEvent::Lenable(l)
+                | KeymapEvent::Lhold(l)
+                | KeymapEvent::Ltap(l)
+                | KeymapEvent::LhtK(l, _) => layers.push(*l),
+                KeymapEvent::LhtL(l, l2) => layers.extend([*l, *l2]),
+                _ => {}
+            }
+        }
+        layers
     }
 }
\ No newline at end of file
@@ -4,3 +4,5 @@ pub mod process;
 pub mod layer;
 pub mod switcher;
 pub mod keys;
+pub mod xkb;
+pub mod krita;
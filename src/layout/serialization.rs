@@ -1,12 +1,36 @@
-use evdev::Key;
-use toml;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
-use super::keys::{G, S};
+use evdev::{Key, RelativeAxisType};
+use toml::{Table, Value};
+
+use super::keys::{action, unicode, KeyGroup, UnicodeMethod, G};
+use super::krita::KritaShortcuts;
 use super::layer::Layer;
+use super::switcher::{LayerOrder, LayerSwitcher};
+use super::xkb::XkbLayout;
+use super::types::{KeymapEvent, LayerStatus, Orientation};
 use super::types::KeymapEvent::{
-    Inh, Kg, Klong, Lactivate, Ldisable, Lhold, LhtK, Lmove, Ltap, No, Pass,
+    Kg, Klong, Lhold, LhtK, Ltoggle, No, Oflip, Pass,
 };
 
+/// Layers of a layout and the options applying to all of them
+pub struct Layout {
+    pub layers: Vec<Layer>,
+    pub layer_order: LayerOrder,
+}
+
+impl Layout {
+    /// Layer switcher running the layout
+    pub fn switcher(&self) -> LayerSwitcher<'_> {
+        let mut switcher = LayerSwitcher::new(&self.layers);
+        switcher.set_layer_order(self.layer_order);
+        switcher
+    }
+}
+
 /*
 
 ( CCW=10 ROT CW=11 ) [ 0 ][ 1 ][ 2 ][ 6 ]
@@ -21,7 +45,15 @@ use super::types::KeymapEvent::{
 
  */
 
-pub fn load_layout(s: &str) -> Vec<Layer> {
+pub fn load_layout(_s: &str, keyboard: &XkbLayout, krita: &KritaShortcuts) -> Result<Layout, String> {
+    // Shortcuts are written by symbol or as Krita actions and resolved
+    // using the keyboard layout and the Krita shortcut configuration
+    let kbd = |s: &str| {
+        krita
+            .shortcut(s, keyboard)
+            .map_err(|e| format!("Cannot resolve shortcut: {}", e))
+    };
+
     // Layer 0 - default
     let keymap_default = vec![
         // blocks
@@ -29,38 +61,38 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
             // rows
             vec![
                 /*  0  */
-                No,
+                Ltoggle(6),
                 /*  1  */
                 No,
                 /*  2  */
-                Klong(G(), G().k(Key::KEY_DELETE)),
+                Klong(G(), kbd("action:clear")?),
                 /*  3  */
                 Lhold(3),
                 /*  4  */
-                LhtK(1, G().k(Key::KEY_B)),
+                LhtK(1, kbd("action:KritaShape/KisToolBrush")?),
                 /*  5  */
                 LhtK(4, G()),
                 /*  6  */
-                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Z).p(),
+                Kg(kbd("action:edit_undo")?),
                 /*  7  */
-                LhtK(5, G().k(Key::KEY_INSERT)),
+                LhtK(5, kbd("action:add_new_paint_layer")?),
                 /*  8  */
-                LhtK(2, G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_E)),
+                LhtK(2, kbd("Shift+E")?),
                 /*  9  */
-                Klong(
-                    G().k(Key::KEY_F12),
-                    G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_A),
-                ),
+                Klong(kbd("F12")?, kbd("action:deselect")?),
                 /* CCW */
-                G().k(Key::KEY_MINUS).p(),
+                Kg(kbd("action:view_zoom_out")?),
                 /*  CW */
-                G().k(Key::KEY_SLASH).p(), // should be minus and equals
+                Kg(kbd("/")?), // should be minus and equals
             ],
         ],
     ];
 
     let default_layer = Layer {
         status_on_reset: super::types::LayerStatus::LayerActive,
+        groups: vec![],
+        exclusive_group: None,
+        priority: 0,
         inherit: None,
         on_active_keys: vec![],
         disable_active_on_press: false,
@@ -84,7 +116,7 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
                 /*  2  */
                 No,
                 /*  3  */
-                G().k(Key::KEY_K).p(),
+                Kg(kbd("action:make_brush_color_darker")?),
                 /*  4  */
                 No,
                 /*  5  */
@@ -92,15 +124,15 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
                 /*  6  */
                 No,
                 /*  7  */
-                G().k(Key::KEY_L).p(),
+                Kg(kbd("action:make_brush_color_lighter")?),
                 /*  8  */
-                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_SPACE).p(),
+                Kg(kbd("Ctrl+Space")?),
                 /*  9  */
                 No,
                 /* CCW */
-                G().k(Key::KEY_RIGHTBRACE).p(),
+                Kg(kbd("action:increase_brush_size")?),
                 /*  CW */
-                G().k(Key::KEY_LEFTBRACE).p(),
+                Kg(kbd("action:decrease_brush_size")?),
             ],
         ],
     ];
@@ -121,25 +153,25 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
             // rows
             vec![
                 /*  0  */
-                G().k(Key::KEY_ESC).p(),
+                Kg(kbd("Esc")?),
                 /*  1  */
-                G().k(Key::KEY_5).p(),
+                Kg(kbd("5")?),
                 /*  2  */
-                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_T).p(),
+                Kg(kbd("action:KisToolTransform")?),
                 /*  3  */
                 No,
                 /*  4  */
-                G().k(Key::KEY_ENTER).p(),
+                Kg(kbd("Enter")?),
                 /*  5  */
                 No,
                 /*  6  */
                 No,
                 /*  7  */
-                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_SPACE).p(),
+                Kg(kbd("Ctrl+Space")?),
                 /*  8  */
                 No,
                 /*  9  */
-                G().k(Key::KEY_T).p(),
+                Kg(kbd("action:KritaTransform/KisToolMove")?),
                 /* CCW */
                 No,
                 /*  CW */
@@ -172,24 +204,21 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
                 /*  3  */
                 No,
                 /*  4  */
-                G().k(Key::KEY_5).p(),
+                Kg(kbd("5")?),
                 /*  5  */
                 No,
                 /*  6  */
-                G().k(Key::KEY_LEFTCTRL)
-                    .k(Key::KEY_LEFTSHIFT)
-                    .k(Key::KEY_Z)
-                    .p(),
+                Kg(kbd("action:edit_redo")?),
                 /*  7  */
                 No,
                 /*  8  */
-                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_SPACE).p(),
+                Kg(kbd("Ctrl+Space")?),
                 /*  9  */
                 No,
                 /* CCW */
-                G().k(Key::KEY_6).p(),
+                Kg(kbd("6")?),
                 /*  CW */
-                G().k(Key::KEY_4).p(),
+                Kg(kbd("4")?),
             ],
         ],
     ];
@@ -218,7 +247,7 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
 
     let draw_layer = Layer {
         status_on_reset: super::types::LayerStatus::LayerPassthrough,
-        on_active_keys: vec![Key::KEY_V],
+        on_active_keys: kbd("V")?.get_used_keys(),
         disable_active_on_press: true,
         keymap: keymap_pass,
         ..default_layer.clone()
@@ -247,7 +276,7 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
                 /*  7  */
                 Pass,
                 /*  8  */
-                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_E).p(),
+                Kg(kbd("action:merge_layer")?),
                 /*  9  */
                 Pass,
                 /* CCW */
@@ -267,6 +296,49 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
     };
 
 
+    // Layer 6 - Media and system control
+    let keymap_media = vec![
+        // blocks
+        vec![
+            // rows
+            vec![
+                /*  0  */
+                Pass,
+                /*  1  */
+                Kg(action("mute").unwrap()),
+                /*  2  */
+                Oflip,
+                /*  3  */
+                Kg(action("previous_track").unwrap()),
+                /*  4  */
+                Kg(action("play_pause").unwrap()),
+                /*  5  */
+                Kg(action("next_track").unwrap()),
+                /*  6  */
+                Kg(action("brightness_up").unwrap()),
+                /*  7  */
+                No,
+                /*  8  */
+                No,
+                /*  9  */
+                Kg(action("brightness_down").unwrap()),
+                /* CCW */
+                Kg(action("volume_down").unwrap()),
+                /*  CW */
+                Kg(action("volume_up").unwrap()),
+            ],
+        ],
+    ];
+
+    let media_layer = Layer {
+        status_on_reset: super::types::LayerStatus::LayerPassthrough,
+        on_active_keys: vec![],
+        disable_active_on_press: false,
+        keymap: keymap_media,
+        ..default_layer.clone()
+    };
+
+
     // Layer ordering, do not change!
 
     let layers = vec![
@@ -276,7 +348,389 @@ pub fn load_layout(s: &str) -> Vec<Layer> {
         view_layer,
         draw_layer,
         layers_layer,
+        media_layer,
     ];
 
-    layers
+    Ok(Layout { layers, layer_order: LayerOrder::Priority })
+}
+
+/// Resolves shortcut strings of a layout file
+struct Resolver<'r> {
+    keyboard: &'r XkbLayout,
+    krita: &'r KritaShortcuts,
+    /// Method typing the unicode actions, None is the hex entry
+    unicode: Option<UnicodeMethod>,
+}
+
+impl Resolver<'_> {
+    fn unicode(&self, c: char) -> Result<KeymapEvent, String> {
+        let method = match &self.unicode {
+            Some(method) => method.clone(),
+            None => UnicodeMethod::hex_entry(self.keyboard)
+                .map_err(|e| format!("The hex entry cannot be typed: {}", e))?,
+        };
+        unicode(c, &method).ok_or(format!("Cannot type \"{}\"", c))
+    }
+
+    fn shortcut(&self, s: &str) -> Result<KeyGroup, String> {
+        if let Some(name) = s.strip_prefix(NAMED_ACTION_PREFIX) {
+            return action(name.trim()).ok_or_else(|| format!("Unknown named action \"{}\"", name));
+        }
+        self.krita.shortcut(s, self.keyboard)
+    }
+}
+
+/// Prefix of shortcut strings referring to a named media action (see `keys::NAMED_ACTIONS`)
+const NAMED_ACTION_PREFIX: &str = "media:";
+
+fn get_int(table: &Table, name: &str) -> Result<Option<usize>, String> {
+    match table.get(name) {
+        None => Ok(None),
+        Some(Value::Integer(i)) if *i >= 0 => Ok(Some(*i as usize)),
+        Some(v) => Err(format!("\"{}\" must be a non-negative integer, found {}", name, v)),
+    }
+}
+
+fn get_str<'v>(table: &'v Table, name: &str) -> Result<Option<&'v str>, String> {
+    match table.get(name) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(v) => Err(format!("\"{}\" must be a string, found {}", name, v)),
+    }
+}
+
+/// Keys of each kind of action table, in the order the kinds are recognized.
+/// The first key names the action, the others are its options.
+const ACTION_TABLES: &[&[&str]] = &[
+    &["key"],
+    &["long", "tap"],
+    &["hold_layer", "tap"],
+    &["hold_tap_layer", "tap"],
+    &["one_shot"],
+    &["lock"],
+    &["unicode"],
+    &["motion", "value"],
+    &["hold", "tap_layer", "tap"],
+    &["tap_layer"],
+    &["move"],
+    &["activate"],
+    &["deactivate"],
+    &["toggle"],
+    &["disable"],
+    &["enable"],
+    &["enable_group"],
+    &["disable_group"],
+    &["orientation"],
+];
+
+/// Reject keys of an action table that are misspelled or do not belong
+/// to the action, they would be silently ignored otherwise
+fn check_action_keys(table: &Table) -> Result<(), String> {
+    if let Some(name) = table
+        .keys()
+        .find(|name| !ACTION_TABLES.iter().any(|keys| keys.contains(&name.as_str())))
+    {
+        return Err(format!("Unknown action key \"{}\"", name));
+    }
+
+    let Some(keys) = ACTION_TABLES.iter().find(|keys| table.contains_key(keys[0])) else {
+        return Ok(());
+    };
+    if let Some(name) = table.keys().find(|name| !keys.contains(&name.as_str())) {
+        return Err(format!("\"{}\" cannot be combined with \"{}\"", name, keys[0]));
+    }
+    if table.contains_key("tap_layer") && table.contains_key("tap") {
+        return Err("\"tap\" cannot be combined with \"tap_layer\"".to_string());
+    }
+
+    Ok(())
+}
+
+/// Keys of a `[[layer]]` table
+const LAYER_KEYS: &[&str] = &[
+    "status",
+    "keys",
+    "default",
+    "inherit",
+    "priority",
+    "groups",
+    "exclusive_group",
+    "on_active_keys",
+    "disable_active_on_press",
+    "timeout_ms",
+    "on_timeout",
+];
+
+/// Top-level keys of a layout file
+const LAYOUT_KEYS: &[&str] = &["layer", "unicode_method", "layer_order"];
+
+/// Reject misspelled keys of a layer or of the layout itself
+fn check_keys(table: &Table, allowed: &[&str], what: &str) -> Result<(), String> {
+    match table.keys().find(|name| !allowed.contains(&name.as_str())) {
+        Some(name) => Err(format!("Unknown {} key \"{}\"", what, name)),
+        None => Ok(()),
+    }
+}
+
+/// Parse the method typing the unicode actions, "hex" for the Ctrl+Shift+U
+/// hex entry or a table with the compose key and the sequence of each character:
+/// { compose = "Menu", sequences = { "©" = ["o", "c"] } }
+fn parse_unicode_method(value: &Value, r: &Resolver) -> Result<Option<UnicodeMethod>, String> {
+    let table = match value {
+        Value::String(s) if s == "hex" => return Ok(None),
+        Value::Table(t) => t,
+        v => return Err(format!("Unknown method {}", v)),
+    };
+
+    if let Some(name) = table.keys().find(|name| !["compose", "sequences"].contains(&name.as_str())) {
+        return Err(format!("Unknown key \"{}\"", name));
+    }
+
+    let compose = get_str(table, "compose")?.ok_or("\"compose\" names the compose key")?;
+    let compose = match r.shortcut(compose)?.get_used_keys()[..] {
+        [key] => key,
+        _ => return Err(format!("\"{}\" is not a single key", compose)),
+    };
+
+    let mut sequences = Vec::new();
+    match table.get("sequences") {
+        None => {}
+        Some(Value::Table(t)) => {
+            for (s, seq) in t {
+                let mut chars = s.chars();
+                let c = match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return Err(format!("\"{}\" is not a single character", s)),
+                };
+                let seq = match seq {
+                    Value::Array(seq) => seq
+                        .iter()
+                        .map(|k| match k {
+                            Value::String(k) => r.shortcut(k),
+                            v => Err(format!("\"{}\": {} is not a shortcut", c, v)),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                    v => return Err(format!("\"{}\" must be an array of shortcuts, found {}", c, v)),
+                };
+                sequences.push((c, seq));
+            }
+        }
+        Some(v) => return Err(format!("\"sequences\" must be a table, found {}", v)),
+    }
+
+    Ok(Some(UnicodeMethod::Compose(compose, sequences)))
+}
+
+/// Parse a key action. A string is a shortcut or one of the keywords
+/// "no", "pass", "inh", "lock_layers" and "flip". A table describes
+/// the other actions, see the README for the list.
+fn parse_action(value: &Value, r: &Resolver) -> Result<KeymapEvent, String> {
+    let table = match value {
+        Value::String(s) => {
+            return match s.as_str() {
+                "no" => Ok(KeymapEvent::No),
+                "pass" => Ok(KeymapEvent::Pass),
+                "inh" => Ok(KeymapEvent::Inh),
+                "lock_layers" => Ok(KeymapEvent::Llock),
+                "flip" => Ok(KeymapEvent::Oflip),
+                s => Ok(KeymapEvent::Kg(r.shortcut(s)?)),
+            }
+        }
+        Value::Table(t) => t,
+        v => return Err(format!("Invalid key action {}", v)),
+    };
+    check_action_keys(table)?;
+
+    let kg = |name: &str| -> Result<Option<KeyGroup>, String> {
+        get_str(table, name)?.map(|s| r.shortcut(s)).transpose()
+    };
+    let layer = |name: &str| get_int(table, name);
+
+    let ev = if let Some(kg) = kg("key")? {
+        KeymapEvent::Kg(kg)
+    } else if let Some(long) = kg("long")? {
+        KeymapEvent::Klong(kg("tap")?.unwrap_or_else(G), long)
+    } else if let Some(l) = layer("hold_layer")? {
+        KeymapEvent::Khl(kg("tap")?.unwrap_or_else(G), l)
+    } else if let Some(l) = layer("hold_tap_layer")? {
+        KeymapEvent::Khtl(kg("tap")?.unwrap_or_else(G), l)
+    } else if let Some(kg) = kg("one_shot")? {
+        KeymapEvent::Kos(kg)
+    } else if let Some(kg) = kg("lock")? {
+        KeymapEvent::Klock(kg)
+    } else if let Some(s) = get_str(table, "unicode")? {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => r.unicode(c)?,
+            _ => return Err(format!("\"unicode\" must be a single character, found \"{}\"", s)),
+        }
+    } else if let Some(axis) = get_str(table, "motion")? {
+        let axis = RelativeAxisType::from_str(axis).map_err(|_| format!("Unknown axis \"{}\"", axis))?;
+        let value = match table.get("value") {
+            Some(Value::Integer(v)) => {
+                i32::try_from(*v).map_err(|_| format!("Motion value {} is out of range", v))?
+            }
+            _ => return Err("\"motion\" needs an integer \"value\"".to_string()),
+        };
+        KeymapEvent::Mrel(axis, value)
+    } else if let Some(l) = layer("hold")? {
+        if let Some(l2) = layer("tap_layer")? {
+            KeymapEvent::LhtL(l, l2)
+        } else if let Some(kg) = kg("tap")? {
+            KeymapEvent::LhtK(l, kg)
+        } else {
+            KeymapEvent::Lhold(l)
+        }
+    } else if let Some(l) = layer("tap_layer")? {
+        KeymapEvent::Ltap(l)
+    } else if let Some(l) = layer("move")? {
+        KeymapEvent::Lmove(l)
+    } else if let Some(l) = layer("activate")? {
+        KeymapEvent::Lactivate(l)
+    } else if let Some(l) = layer("deactivate")? {
+        KeymapEvent::Ldeactivate(l)
+    } else if let Some(l) = layer("toggle")? {
+        KeymapEvent::Ltoggle(l)
+    } else if let Some(l) = layer("disable")? {
+        KeymapEvent::Ldisable(l)
+    } else if let Some(l) = layer("enable")? {
+        KeymapEvent::Lenable(l)
+    } else if let Some(g) = layer("enable_group")? {
+        KeymapEvent::Genable(g)
+    } else if let Some(g) = layer("disable_group")? {
+        KeymapEvent::Gdisable(g)
+    } else if let Some(o) = get_str(table, "orientation")? {
+        match o {
+            "normal" => KeymapEvent::Oset(Orientation::Normal),
+            "rotated" => KeymapEvent::Oset(Orientation::Rotated),
+            o => return Err(format!("Unknown orientation \"{}\"", o)),
+        }
+    } else {
+        return Err(format!("Unknown key action {}", value));
+    };
+
+    Ok(ev)
+}
+
+fn parse_layer(table: &Table, r: &Resolver) -> Result<Layer, String> {
+    check_keys(table, LAYER_KEYS, "layer")?;
+
+    let status = match get_str(table, "status")?.unwrap_or("passthrough") {
+        "active" => LayerStatus::LayerActive,
+        "passthrough" => LayerStatus::LayerPassthrough,
+        "disabled" => LayerStatus::LayerDisabled,
+        s => return Err(format!("Unknown layer status \"{}\"", s)),
+    };
+
+    let keys = match table.get("keys") {
+        None => vec![],
+        Some(Value::Array(keys)) => keys
+            .iter()
+            .enumerate()
+            .map(|(idx, k)| parse_action(k, r).map_err(|e| format!("key {}: {}", idx, e)))
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(v) => return Err(format!("\"keys\" must be an array, found {}", v)),
+    };
+
+    let groups = match table.get("groups") {
+        None => vec![],
+        Some(Value::Array(groups)) => groups
+            .iter()
+            .map(|g| match g {
+                Value::Integer(g) if *g >= 0 => Ok(*g as usize),
+                g => Err(format!("Invalid layer group {}", g)),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(v) => return Err(format!("\"groups\" must be an array, found {}", v)),
+    };
+
+    let disable_active_on_press = match table.get("disable_active_on_press") {
+        None => false,
+        Some(Value::Boolean(b)) => *b,
+        Some(v) => return Err(format!("\"disable_active_on_press\" must be a boolean, found {}", v)),
+    };
+
+    let priority = match table.get("priority") {
+        None => 0,
+        Some(Value::Integer(p)) => {
+            i32::try_from(*p).map_err(|_| format!("\"priority\" {} is out of range", p))?
+        }
+        Some(v) => return Err(format!("\"priority\" must be an integer, found {}", v)),
+    };
+
+    Ok(Layer {
+        status_on_reset: status,
+        groups,
+        exclusive_group: get_int(table, "exclusive_group")?,
+        priority,
+        inherit: get_int(table, "inherit")?,
+        on_active_keys: get_str(table, "on_active_keys")?
+            .map(|s| r.shortcut(s))
+            .transpose()?
+            .map_or(vec![], |kg| kg.get_used_keys()),
+        disable_active_on_press,
+        on_timeout_layer: get_int(table, "on_timeout")?,
+        timeout: get_int(table, "timeout_ms")?.map(|ms| Duration::from_millis(ms as u64)),
+        keymap: vec![vec![keys]],
+        default_action: table
+            .get("default")
+            .map(|d| parse_action(d, r))
+            .transpose()?
+            .unwrap_or(KeymapEvent::Pass),
+    })
+}
+
+/// Parse a layout file. The file contains a `[[layer]]` table for each layer,
+/// the first one is the always active base layer. Shortcuts are resolved using
+/// the keyboard layout and Krita shortcuts.
+pub fn parse_layout(content: &str, keyboard: &XkbLayout, krita: &KritaShortcuts) -> Result<Layout, String> {
+    let doc: Table = content.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    parse_layout_table(&doc, keyboard, krita)
+}
+
+/// Parse an already parsed layout file, see `parse_layout`
+pub fn parse_layout_table(doc: &Table, keyboard: &XkbLayout, krita: &KritaShortcuts) -> Result<Layout, String> {
+    let mut r = Resolver { keyboard, krita, unicode: None };
+    check_keys(doc, LAYOUT_KEYS, "layout")?;
+
+    if let Some(method) = doc.get("unicode_method") {
+        r.unicode = parse_unicode_method(method, &r).map_err(|e| format!("unicode_method: {}", e))?;
+    }
+
+    let layer_order = match get_str(doc, "layer_order")? {
+        None | Some("priority") => LayerOrder::Priority,
+        Some("recent") => LayerOrder::MostRecentlyActivated,
+        Some(o) => return Err(format!("Unknown layer order \"{}\"", o)),
+    };
+
+    let layers = match doc.get("layer") {
+        Some(Value::Array(layers)) => layers,
+        _ => return Err("The layout needs at least one [[layer]]".to_string()),
+    };
+
+    let layers = layers
+        .iter()
+        .enumerate()
+        .map(|(idx, l)| match l {
+            Value::Table(t) => parse_layer(t, &r).map_err(|e| format!("layer {}: {}", idx, e)),
+            _ => Err(format!("layer {}: not a table", idx)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Layer references must point to existing layers
+    for (idx, layer) in layers.iter().enumerate() {
+        for l in layer.get_referenced_layers() {
+            if l >= layers.len() {
+                return Err(format!("layer {}: refers to a missing layer {}", idx, l));
+            }
+        }
+    }
+
+    Ok(Layout { layers, layer_order })
+}
+
+/// Load a layout file, see `parse_layout`
+pub fn load_layout_file(path: &Path, keyboard: &XkbLayout, krita: &KritaShortcuts) -> Result<Layout, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse_layout(&content, keyboard, krita).map_err(|e| format!("{}: {}", path.display(), e))
 }
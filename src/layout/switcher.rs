@@ -1,18 +1,26 @@
 use std::collections::{HashSet, VecDeque};
 use std::time::{Duration, Instant};
 
-use evdev::Key;
+use evdev::{Key, RelativeAxisType};
 
-use crate::kbd_events::KeyStateChange;
+use crate::kbd_events::{KeyStateChange, LONG_PRESS};
+use crate::timer::Deadline;
 
 use super::keys::KeyGroup;
 use super::layer::Layer;
-use super::types::{KeyCoords, KeymapEvent, LayerId, LayerStatus};
+use super::types::{KeyCoords, KeymapEvent, LayerGroupId, LayerId, LayerStatus, Orientation};
 
 const LAYER_KEY: KeyCoords = KeyCoords(255, 255, 255);
 
-/// The key press duration threshold to distinguish between tap and hold
-const HOLD_THRESHOLD_MS: Duration = Duration::from_millis(200);
+/// The maximum delay between two taps of a lockable modifier to latch it
+const DOUBLE_TAP_THRESHOLD_MS: Duration = Duration::from_millis(300);
+
+/// Output of the layer switcher
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Emitted {
+    Key(Key, bool),
+    Motion(RelativeAxisType, i32),
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeyReleaseMode {
@@ -20,6 +28,38 @@ pub enum KeyReleaseMode {
     ForceClick,
 }
 
+/// Order in which active layers are consulted when resolving a key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerOrder {
+    /// Higher priority wins, layers with the same priority are ordered
+    /// by their position in the layer list (the last one wins)
+    Priority,
+    /// Higher priority wins, layers with the same priority are ordered
+    /// by the time of activation (the most recently activated wins)
+    MostRecentlyActivated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StickyState {
+    /// Tapped, waiting for the next emitted key. Records the time of the tap.
+    Armed(Instant),
+    /// Pressed together with the key at the recorded coordinates,
+    /// released when that key is released.
+    Applied(KeyCoords),
+    /// Latched by a double tap, held until tapped again.
+    Locked,
+}
+
+#[derive(Clone)]
+pub struct StickyEntry<'a> {
+    /// The key that armed the modifier
+    pub(super) coords: KeyCoords,
+    pub(super) keys: &'a KeyGroup,
+    /// Can the modifier be latched with a double tap?
+    pub(super) lockable: bool,
+    pub(super) state: StickyState,
+}
+
 pub struct LayerSwitcher<'a> {
     /// Static configuration of layers
     pub(super) layers: &'a Vec<Layer>,
@@ -34,15 +74,35 @@ pub struct LayerSwitcher<'a> {
         Option<&'a KeyGroup>,
         Instant,
     )>,
-
-    /// Queue of generated keycodes to issue to the OS
-    emitted_codes: VecDeque<(Key, bool)>,
+    /// One-shot and locked modifiers, independent of the layer state
+    pub(super) sticky: Vec<StickyEntry<'a>>,
+    /// Layer resolution order
+    pub(super) layer_order: LayerOrder,
+    /// Monotonic counter used to record the layer activation order
+    activation_counter: u64,
+    /// Physical orientation of the pad
+    pub(super) orientation: Orientation,
+    /// The key press duration threshold to distinguish between tap and hold
+    hold_threshold: Duration,
+    /// Physical keys held down with the logical position they were pressed as,
+    /// so an orientation change does not mix up their release
+    held: Vec<(KeyCoords, KeyCoords)>,
+    /// Time of the last key event or tick, layer timeouts start from it
+    now: Instant,
+
+    /// Queue of generated keycodes and pointer movements to issue to the OS,
+    /// in the order they were generated
+    emitted: VecDeque<Emitted>,
 }
 
 #[derive(Clone)]
 pub struct LayerStackEntry {
     pub(super) status: LayerStatus,
     pub(super) active_keys: bool,
+    /// Value of the activation counter at the last activation
+    pub(super) activated: u64,
+    /// The layer times out at this time, see `Layer::timeout`
+    pub(super) expires: Option<Instant>,
 }
 
 impl<'a> LayerSwitcher<'a> {
@@ -51,7 +111,14 @@ impl<'a> LayerSwitcher<'a> {
             layers,
             layer_stack: Vec::new(),
             presses: Vec::new(),
-            emitted_codes: VecDeque::new(),
+            sticky: Vec::new(),
+            layer_order: LayerOrder::Priority,
+            activation_counter: 0,
+            orientation: Orientation::Normal,
+            hold_threshold: LONG_PRESS,
+            held: Vec::new(),
+            now: Instant::now(),
+            emitted: VecDeque::new(),
         }
     }
 
@@ -64,16 +131,41 @@ impl<'a> LayerSwitcher<'a> {
                 status: layer.status_on_reset,
                 active_keys: layer.status_on_reset != LayerStatus::LayerDisabled
                     && layer.status_on_reset != LayerStatus::LayerPassthrough,
+                activated: 0,
+                expires: None,
             })
         }
         self.layer_stack[0].status = LayerStatus::LayerActive;
         self.presses.clear();
-        self.emitted_codes.clear();
+        self.sticky.clear();
+        self.activation_counter = 0;
+        self.held.clear();
+        self.emitted.clear();
+    }
+
+    /// Select the order in which active layers are consulted
+    pub fn set_layer_order(&mut self, order: LayerOrder) {
+        self.layer_order = order;
+    }
+
+    /// Set the key press duration threshold to distinguish between tap and hold,
+    /// it has to match the long press threshold of the change detector
+    pub fn set_hold_threshold(&mut self, threshold: Duration) {
+        self.hold_threshold = threshold;
+    }
+
+    /// Select the physical orientation of the pad
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+    }
+
+    pub fn get_orientation(&self) -> Orientation {
+        self.orientation
     }
 
     /// Disable layer for good. No activation will enable it
     /// until is gets enabled explicitly.
-    fn layer_disable(&mut self, idx: LayerId) {
+    pub fn layer_disable(&mut self, idx: LayerId) {
         // The lowest layer is always active
         if idx == 0 {
             return;
@@ -92,6 +184,35 @@ impl<'a> LayerSwitcher<'a> {
         self.layer_stack[idx].status = LayerStatus::LayerDisabled;
     }
 
+    /// Enable a disabled layer. The layer is left in the passthrough
+    /// state and has to be activated separately.
+    pub fn layer_enable(&mut self, idx: LayerId) {
+        if self.layer_stack[idx].status != LayerStatus::LayerDisabled {
+            return;
+        }
+
+        self.layer_stack[idx].status = LayerStatus::LayerPassthrough;
+        self.layer_stack[idx].active_keys = false;
+    }
+
+    /// Enable all layers that belong to the layer group `group`
+    pub fn group_enable(&mut self, group: LayerGroupId) {
+        for idx in 0..self.layers.len() {
+            if self.layers[idx].groups.contains(&group) {
+                self.layer_enable(idx);
+            }
+        }
+    }
+
+    /// Disable all layers that belong to the layer group `group`
+    pub fn group_disable(&mut self, group: LayerGroupId) {
+        for idx in 0..self.layers.len() {
+            if self.layers[idx].groups.contains(&group) {
+                self.layer_disable(idx);
+            }
+        }
+    }
+
     /// Set layer to passthrough and disable its rules
     fn layer_deactivate(&mut self, idx: LayerId) {
         // The lowest layer is always active
@@ -110,6 +231,7 @@ impl<'a> LayerSwitcher<'a> {
         }
 
         self.layer_stack[idx].status = LayerStatus::LayerPassthrough;
+        self.layer_stack[idx].expires = None;
 
         self.on_layer_deactivation(idx);
     }
@@ -130,6 +252,30 @@ impl<'a> LayerSwitcher<'a> {
         self.on_layer_activation(idx);
     }
 
+    /// Activate layer when it is inactive, deactivate it otherwise
+    fn layer_toggle(&mut self, idx: LayerId) {
+        match self.layer_stack[idx].status {
+            // Disabled layer, ignore action
+            LayerStatus::LayerDisabled => {}
+            LayerStatus::LayerPassthrough => self.layer_activate(idx),
+            _ => self.layer_deactivate(idx),
+        }
+    }
+
+    /// Convert all layers held active by a key press to permanently active layers
+    fn layer_lock(&mut self) {
+        for l in self.layer_stack.iter_mut() {
+            match l.status {
+                LayerStatus::LayerActiveUntilKeyRelease(_)
+                | LayerStatus::LayerHoldAndTapToL(..)
+                | LayerStatus::LayerHoldAndTapKey(..) => {
+                    l.status = LayerStatus::LayerActive;
+                }
+                _ => {}
+            }
+        }
+    }
+
     /// Activate layer and keep it activated until `coords` key is kept pressed
     fn layer_hold(&mut self, idx: LayerId, coords: KeyCoords) {
         // Disabled layer, ignore action
@@ -165,7 +311,7 @@ impl<'a> LayerSwitcher<'a> {
 
     /// Activate layer `idx` and keep it activated while `coords` is pressed.
     /// At `coords` release check elapsed time and activate layer `idx2` when
-    /// the press duration was shorter than `HOLD_THRESHOLD_MS`
+    /// the press duration was shorter than the hold threshold
     fn layer_hold_tap(&mut self, idx: LayerId, idx2: LayerId, coords: KeyCoords, t: Instant) {
         // Disabled layer, ignore action
         if self.layer_stack[idx].status == LayerStatus::LayerDisabled {
@@ -183,7 +329,7 @@ impl<'a> LayerSwitcher<'a> {
 
     /// Activate layer `idx` and keep it activated while `coords` is pressed.
     /// At `coords` release check elapsed time and emit configured keys when
-    /// the press duration was shorter than `HOLD_THRESHOLD_MS`
+    /// the press duration was shorter than the hold threshold
     fn layer_hold_key(
         &mut self,
         activate_idx: LayerId,
@@ -217,7 +363,7 @@ impl<'a> LayerSwitcher<'a> {
             if idx == l_idx {
                 continue;
             }
-            self.layer_deactivate(idx);
+            self.layer_deactivate(l_idx);
         }
 
         self.layer_activate(idx);
@@ -225,11 +371,24 @@ impl<'a> LayerSwitcher<'a> {
 
     /// Perform this on each layer activation
     fn on_layer_activation(&mut self, idx: LayerId) {
+        // Only one layer from an exclusive group can be active at a time
+        if let Some(group) = self.layers[idx].exclusive_group {
+            for l_idx in 0..self.layers.len() {
+                if l_idx != idx && self.layers[l_idx].exclusive_group == Some(group) {
+                    self.layer_deactivate(l_idx);
+                }
+            }
+        }
+
         let keys = &self.layers[idx].on_active_keys;
         for k in keys {
-            self.emit_keycodes(LAYER_KEY, &k, true);
+            self.emit_keycodes(LAYER_KEY, k, true);
         }
         self.layer_stack[idx].active_keys = true;
+
+        self.activation_counter += 1;
+        self.layer_stack[idx].activated = self.activation_counter;
+        self.layer_stack[idx].expires = self.layers[idx].timeout.map(|d| self.now + d);
     }
 
     /// Perform this on each layer deactivation
@@ -247,9 +406,9 @@ impl<'a> LayerSwitcher<'a> {
     }
 
     fn before_key_press(&mut self, layer: LayerId) {
-        if self.layers[layer].disable_active_on_press && (&self.layer_stack)[layer].active_keys {
-            for k in (&self.layers[layer].on_active_keys).into_iter().rev() {
-                self.emit_keycodes(LAYER_KEY, &k, false);
+        if self.layers[layer].disable_active_on_press && self.layer_stack[layer].active_keys {
+            for k in self.layers[layer].on_active_keys.iter().rev() {
+                self.emit_keycodes(LAYER_KEY, k, false);
             }
             self.layer_stack[layer].active_keys = false;
         }
@@ -275,7 +434,7 @@ impl<'a> LayerSwitcher<'a> {
 
         // Re-enable active keys
         for k in &self.layers[layer].on_active_keys {
-            self.emit_keycodes(LAYER_KEY, &k, true);
+            self.emit_keycodes(LAYER_KEY, k, true);
         }
         self.layer_stack[layer].active_keys = true;
     }
@@ -289,28 +448,30 @@ impl<'a> LayerSwitcher<'a> {
         force_click: bool,
     ) {
         self.before_key_press(srclayer);
+        self.sticky_apply(coords);
         for k in &kg.mask {
-            self.emit_keycodes(coords, &k, false);
+            self.emit_keycodes(coords, k, false);
         }
 
         for k in &kg.keys {
-            self.emit_keycodes(coords, &k, true);
+            self.emit_keycodes(coords, k, true);
             if kg.sequential {
-                self.emit_keycodes(coords, &k, false);
+                self.emit_keycodes(coords, k, false);
             }
         }
 
         if !kg.sequential && force_click {
-            for k in (&kg.keys).into_iter().rev() {
+            for k in kg.keys.iter().rev() {
                 self.emit_keycodes(coords, k, false);
             }
         }
 
         if kg.sequential || force_click {
-            for k in (&kg.mask).into_iter().rev() {
+            for k in kg.mask.iter().rev() {
                 self.emit_keycodes(coords, k, true);
             }
 
+            self.sticky_release(coords);
             self.after_key_release(srclayer);
         } else {
             self.presses
@@ -323,18 +484,84 @@ impl<'a> LayerSwitcher<'a> {
             return; // sequential mode should have been released
         }
 
-        for k in (&kg.keys).into_iter().rev() {
-            self.emit_keycodes(coords, &k, false);
+        for k in kg.keys.iter().rev() {
+            self.emit_keycodes(coords, k, false);
         }
 
-        for k in (&kg.mask).into_iter().rev() {
+        for k in kg.mask.iter().rev() {
             self.emit_keycodes(coords, k, true);
         }
 
+        self.sticky_release(coords);
         self.after_key_release(srclayer);
     }
 
+    /// Process a tap of a one-shot (`lockable` == false) or a lockable modifier.
+    /// The first tap arms the modifier for the next emitted key, a second tap
+    /// cancels it, unless it is lockable and the second tap came quickly
+    /// enough. In that case the modifier gets latched until the next tap.
+    fn sticky_tap(&mut self, kg: &'a KeyGroup, coords: KeyCoords, lockable: bool, t: Instant) {
+        let idx = self.sticky.iter().position(|e| {
+            e.coords == coords && !matches!(e.state, StickyState::Applied(_))
+        });
+
+        match idx.map(|idx| (idx, self.sticky[idx].state)) {
+            None => self.sticky.push(StickyEntry {
+                coords,
+                keys: kg,
+                lockable,
+                state: StickyState::Armed(t),
+            }),
+            Some((idx, StickyState::Armed(t0))) => {
+                if self.sticky[idx].lockable && t - t0 < DOUBLE_TAP_THRESHOLD_MS {
+                    for k in &kg.keys {
+                        self.emit_keycodes(coords, k, true);
+                    }
+                    self.sticky[idx].state = StickyState::Locked;
+                } else {
+                    self.sticky.remove(idx);
+                }
+            }
+            Some((idx, _)) => {
+                for k in kg.keys.iter().rev() {
+                    self.emit_keycodes(coords, k, false);
+                }
+                self.sticky.remove(idx);
+            }
+        }
+    }
+
+    /// Press all armed one-shot modifiers and bind them to the key at `coords`
+    fn sticky_apply(&mut self, coords: KeyCoords) {
+        for idx in 0..self.sticky.len() {
+            if let StickyState::Armed(_) = self.sticky[idx].state {
+                let kg = self.sticky[idx].keys;
+                for k in &kg.keys {
+                    self.emit_keycodes(coords, k, true);
+                }
+                self.sticky[idx].state = StickyState::Applied(coords);
+            }
+        }
+    }
+
+    /// Release all one-shot modifiers bound to the key at `coords`
+    fn sticky_release(&mut self, coords: KeyCoords) {
+        let mut idx = 0;
+        while idx < self.sticky.len() {
+            if self.sticky[idx].state == StickyState::Applied(coords) {
+                let kg = self.sticky[idx].keys;
+                for k in kg.keys.iter().rev() {
+                    self.emit_keycodes(coords, k, false);
+                }
+                self.sticky.remove(idx);
+            } else {
+                idx += 1;
+            }
+        }
+    }
+
     /// Get the number of currently recorded presses originating from `layer`
+    #[allow(dead_code)]
     pub(crate) fn active_keys_from_layer(&self, layer: LayerId) -> usize {
         self.presses.iter().fold(
             0,
@@ -365,7 +592,7 @@ impl<'a> LayerSwitcher<'a> {
             KeymapEvent::Pass => {}
 
             KeymapEvent::Kg(kg) => {
-                self.keygroup_press(&kg, coords, srclayer, t, false);
+                self.keygroup_press(kg, coords, srclayer, t, false);
             }
             KeymapEvent::Klong(kshort, _) => {
                 // Record the press with a short key release entry
@@ -389,14 +616,31 @@ impl<'a> LayerSwitcher<'a> {
                     .push((srclayer, coords, KeyReleaseMode::ForceClick, Some(k), t));
             }
 
+            KeymapEvent::Ku(_, kgs) => {
+                for kg in kgs {
+                    self.keygroup_press(kg, coords, srclayer, t, true);
+                }
+            }
+            KeymapEvent::Mrel(axis, value) => self.emitted.push_back(Emitted::Motion(*axis, *value)),
+
+            KeymapEvent::Kos(kg) => self.sticky_tap(kg, coords, false, t),
+            KeymapEvent::Klock(kg) => self.sticky_tap(kg, coords, true, t),
+
             KeymapEvent::Lmove(idx) => self.layer_move(*idx),
             KeymapEvent::Lhold(idx) => self.layer_hold(*idx, coords),
             KeymapEvent::Ltap(idx) => self.layer_tap(*idx, coords),
             KeymapEvent::Lactivate(idx) => self.layer_activate(*idx),
+            KeymapEvent::Ltoggle(idx) => self.layer_toggle(*idx),
+            KeymapEvent::Llock => self.layer_lock(),
+            KeymapEvent::Oset(orientation) => self.orientation = *orientation,
+            KeymapEvent::Oflip => self.orientation = self.orientation.flipped(),
 
             KeymapEvent::Ldisable(idx) => {
                 self.layer_disable(*idx);
             }
+            KeymapEvent::Lenable(idx) => self.layer_enable(*idx),
+            KeymapEvent::Genable(group) => self.group_enable(*group),
+            KeymapEvent::Gdisable(group) => self.group_disable(*group),
             KeymapEvent::Ldeactivate(idx) => {
                 self.layer_deactivate(*idx);
             }
@@ -421,24 +665,22 @@ impl<'a> LayerSwitcher<'a> {
         let press = press.unwrap();
 
         // Long press was still too short, wait for another one
-        if t - press.4 <= HOLD_THRESHOLD_MS {
+        if t - press.4 <= self.hold_threshold {
             return;
         }
 
         // In case no release events were recorded consult the keymap and press the long keys
         match self.layers[press.1].get_key_event(coords) {
-            KeymapEvent::Klong(_, klong) => {
-                // When LongPress arrives for the first time, the short click is configured.
-                // Replace it with the Long press.
-                // When LongPress arrives for the second time, the long press is configured
-                // without force_click, use that as a hint that no change is needed.
-                if press.2 == KeyReleaseMode::ForceClick {
-                    // Remove the short press entry
-                    self.presses.swap_remove(press.0);
-
-                    // Emit and record the long press entry
-                    self.keygroup_press(&klong, coords, press.1, t, true);
-                }
+            // When LongPress arrives for the first time, the short click is configured.
+            // Replace it with the Long press.
+            // When LongPress arrives for the second time, the long press is configured
+            // without force_click, use that as a hint that no change is needed.
+            KeymapEvent::Klong(_, klong) if press.2 == KeyReleaseMode::ForceClick => {
+                // Remove the short press entry
+                self.presses.swap_remove(press.0);
+
+                // Emit and record the long press entry
+                self.keygroup_press(klong, coords, press.1, t, true);
             }
             KeymapEvent::Khtl(_, l) => {
                 // Remove the short press entry
@@ -467,13 +709,13 @@ impl<'a> LayerSwitcher<'a> {
         Instant,
     )> {
         for (idx, (layer, coord, release_mode, kgroup, t)) in
-            (&self.presses).into_iter().enumerate()
+            self.presses.iter().enumerate()
         {
             if *coord == coords {
                 return Some((idx, *layer, *release_mode, *kgroup, *t));
             }
         }
-        return None;
+        None
     }
 
     /// This is the main key release handling function
@@ -481,43 +723,34 @@ impl<'a> LayerSwitcher<'a> {
         // Deactivate layers
         for (idx, l) in self.layer_stack.clone().into_iter().enumerate() {
             match l.status {
-                LayerStatus::LayerActiveUntilKeyRelease(wait_coords) => {
-                    if wait_coords == coords {
-                        self.layer_deactivate(idx);
-                    }
+                LayerStatus::LayerActiveUntilKeyRelease(wait_coords) if wait_coords == coords => {
+                    self.layer_deactivate(idx);
                 }
-                LayerStatus::LayerActiveUntilKeyReleaseTap(wait_coords) => {
-                    if wait_coords == coords {
-                        self.layer_stack[idx].status = LayerStatus::LayerActiveUntilAnyKeyPress;
-                    }
+                LayerStatus::LayerActiveUntilKeyReleaseTap(wait_coords) if wait_coords == coords => {
+                    self.layer_stack[idx].status = LayerStatus::LayerActiveUntilAnyKeyPress;
                 }
-                LayerStatus::LayerHoldAndTapKey(wait_coords, t0, lidx) => {
-                    if wait_coords == coords {
-                        self.layer_deactivate(idx);
-
-                        let elapsed = t - t0;
-                        if elapsed < HOLD_THRESHOLD_MS {
-                            let kev = self.layers[lidx].get_key_event(wait_coords);
-                            match kev {
-                                KeymapEvent::LhtK(_, k) => {
-                                    self.keygroup_press(&k, coords, lidx, t, true);
-                                }
-                                _ => {}
-                            }
+                LayerStatus::LayerHoldAndTapKey(wait_coords, t0, lidx) if wait_coords == coords => {
+                    self.layer_deactivate(idx);
+
+                    let elapsed = t - t0;
+                    if elapsed < self.hold_threshold {
+                        let kev = self.layers[lidx].get_key_event(wait_coords);
+                        if let KeymapEvent::LhtK(_, k) = kev {
+                            self.keygroup_press(k, coords, lidx, t, true);
                         }
                     }
                 }
-                LayerStatus::LayerHoldAndTapToL(wait_coords, t0, next_layer) => {
-                    if wait_coords == coords {
-                        self.layer_deactivate(idx);
-
-                        let elapsed = t - t0;
-                        if elapsed < HOLD_THRESHOLD_MS {
-                            self.layer_tap(next_layer, coords);
-                            // This is the first release already, just wait for next key
-                            self.layer_stack[next_layer].status =
-                                LayerStatus::LayerActiveUntilAnyKeyPress;
-                        }
+                LayerStatus::LayerHoldAndTapToL(wait_coords, t0, next_layer)
+                    if wait_coords == coords =>
+                {
+                    self.layer_deactivate(idx);
+
+                    let elapsed = t - t0;
+                    if elapsed < self.hold_threshold {
+                        self.layer_tap(next_layer, coords);
+                        // This is the first release already, just wait for next key
+                        self.layer_stack[next_layer].status =
+                            LayerStatus::LayerActiveUntilAnyKeyPress;
                     }
                 }
                 _ => {}
@@ -537,9 +770,9 @@ impl<'a> LayerSwitcher<'a> {
         if let Some(kg) = press.3 {
             if press.2 == KeyReleaseMode::ForceClick {
                 // consult the keymap and send the short keys as full click
-                self.keygroup_press(&kg, coords, press.1, t, true);
+                self.keygroup_press(kg, coords, press.1, t, true);
             } else {
-                self.keygroup_release(&kg, coords, press.1);
+                self.keygroup_release(kg, coords, press.1);
             }
         }
 
@@ -554,7 +787,7 @@ impl<'a> LayerSwitcher<'a> {
     ) -> (LayerId, &'a KeymapEvent) {
         let mut layer_idx = idx;
         loop {
-            let ev = (&self.layers)[layer_idx].get_key_event(coords);
+            let ev = self.layers[layer_idx].get_key_event(coords);
             match ev {
                 KeymapEvent::No => return (idx, ev),
 
@@ -564,18 +797,31 @@ impl<'a> LayerSwitcher<'a> {
                 KeymapEvent::Khl(..) => return (idx, ev),
                 KeymapEvent::Khtl(..) => return (idx, ev),
 
+                KeymapEvent::Ku(..) => return (idx, ev),
+                KeymapEvent::Mrel(..) => return (idx, ev),
+
+                KeymapEvent::Kos(_) => return (idx, ev),
+                KeymapEvent::Klock(_) => return (idx, ev),
+
                 KeymapEvent::Lmove(_) => return (idx, ev),
                 KeymapEvent::Lhold(_) => return (idx, ev),
                 KeymapEvent::Ltap(_) => return (idx, ev),
                 KeymapEvent::Lactivate(_) => return (idx, ev),
                 KeymapEvent::Ldeactivate(_) => return (idx, ev),
+                KeymapEvent::Ltoggle(_) => return (idx, ev),
+                KeymapEvent::Llock => return (idx, ev),
+                KeymapEvent::Oset(_) => return (idx, ev),
+                KeymapEvent::Oflip => return (idx, ev),
                 KeymapEvent::Ldisable(_) => return (idx, ev),
+                KeymapEvent::Lenable(_) => return (idx, ev),
+                KeymapEvent::Genable(_) => return (idx, ev),
+                KeymapEvent::Gdisable(_) => return (idx, ev),
                 KeymapEvent::LhtL(..) => return (idx, ev),
                 KeymapEvent::LhtK(..) => return (idx, ev),
 
                 KeymapEvent::Inh => {
                     // find the layer this inherits from
-                    if let Some(next_p_idx) = (&self.layers)[layer_idx].inherit {
+                    if let Some(next_p_idx) = self.layers[layer_idx].inherit {
                         // TODO check that the parent layer ID is valid
                         layer_idx = next_p_idx;
                     } else {
@@ -586,19 +832,14 @@ impl<'a> LayerSwitcher<'a> {
             }
         }
 
-        return (0, &(&self.layers)[layer_idx].default_action);
+        (0, &self.layers[layer_idx].default_action)
     }
 
     /// Resolve the keymap event currently mapped to key `coords`. Take into
     /// account the state of all layers and inheritance.
     /// Returns the keymap event and the layer it came from
     fn get_key_event(&self, coords: KeyCoords) -> (LayerId, Option<&'a KeymapEvent>) {
-        for (idx, l) in (&self.layer_stack).into_iter().enumerate().rev() {
-            // Skip disabled layers
-            if l.status == LayerStatus::LayerDisabled || l.status == LayerStatus::LayerPassthrough {
-                continue;
-            }
-
+        for idx in self.get_resolution_order() {
             let (_layerid, ev) = self.get_key_event_inheritance(coords, idx);
             if *ev != KeymapEvent::Pass {
                 return (idx, Some(ev));
@@ -608,9 +849,25 @@ impl<'a> LayerSwitcher<'a> {
         (0, None)
     }
 
+    /// Get the list of active layers in the order they should be consulted
+    /// during key resolution. The winning layer is first.
+    fn get_resolution_order(&self) -> Vec<LayerId> {
+        let mut order: Vec<LayerId> = self.get_active_layers();
+
+        order.sort_by_key(|idx| {
+            let activated = match self.layer_order {
+                LayerOrder::Priority => 0,
+                LayerOrder::MostRecentlyActivated => self.layer_stack[*idx].activated,
+            };
+            std::cmp::Reverse((self.layers[*idx].priority, activated, *idx))
+        });
+
+        order
+    }
+
     /// Record a keycode event to be sent to the OS
     fn emit_keycodes(&mut self, _coords: KeyCoords, k: &evdev::Key, pressed: bool) {
-        self.emitted_codes.push_back((*k, pressed));
+        self.emitted.push_back(Emitted::Key(*k, pressed));
     }
 
     /// This is the input entrypoint for external key events. Right now everything is processed
@@ -620,29 +877,68 @@ impl<'a> LayerSwitcher<'a> {
         T: Into<KeyCoords>,
     {
         assert!(
-            self.layer_stack.len() > 0,
+            !self.layer_stack.is_empty(),
             "The layout engine was not started."
         );
+        let t = t.into();
+        // Layers that timed out before the event do not handle it
+        self.tick(t);
         match ev {
-            KeyStateChange::Pressed(k) => self.process_keyevent_press(k.into(), t.into()),
-            KeyStateChange::Released(k) => self.process_keyevent_release(k.into(), t.into()),
+            KeyStateChange::Pressed(k) => {
+                let physical = k.into();
+                let k = self.orientation.logical(physical);
+                self.held.push((physical, k));
+                self.process_keyevent_press(k, t);
+            }
+            KeyStateChange::Released(k) => {
+                let k = self.held_logical(k.into(), true);
+                self.process_keyevent_release(k, t);
+            }
             KeyStateChange::Click(k) => {
-                let k = k.into();
-                let ti = t.into();
-                self.process_keyevent_press(k, ti);
-                self.process_keyevent_release(k, ti);
+                let k = self.orientation.logical(k.into());
+                self.process_keyevent_press(k, t);
+                self.process_keyevent_release(k, t);
+            }
+            KeyStateChange::LongPress(k) => {
+                let k = self.held_logical(k.into(), false);
+                self.process_keyevent_long_press(k, t);
+            }
+        }
+    }
+
+    /// Time passed without a key event. Deactivate the layers whose timeout
+    /// elapsed and activate their `on_timeout_layer`.
+    pub fn tick(&mut self, t: Instant) {
+        self.now = t;
+        for idx in 0..self.layer_stack.len() {
+            if self.layer_stack[idx].expires.is_some_and(|e| e <= t) {
+                self.layer_stack[idx].expires = None;
+                self.layer_deactivate(idx);
+                if let Some(next) = self.layers[idx].on_timeout_layer {
+                    self.layer_activate(next);
+                }
             }
-            KeyStateChange::LongPress(k) => self.process_keyevent_long_press(k.into(), t.into()),
         }
     }
 
-    /// Consume all queued keycode events via the `renderer` closure.
+    /// Logical position of a held physical key, `forget` removes the record
+    /// once the key is released
+    fn held_logical(&mut self, physical: KeyCoords, forget: bool) -> KeyCoords {
+        match self.held.iter().position(|(p, _)| *p == physical) {
+            Some(idx) if forget => self.held.swap_remove(idx).1,
+            Some(idx) => self.held[idx].1,
+            None => self.orientation.logical(physical),
+        }
+    }
+
+    /// Consume all queued keycode events and pointer movements via
+    /// the `renderer` closure, in the order they were generated.
     pub fn render<F>(&mut self, mut renderer: F)
     where
-        F: FnMut(Key, bool),
+        F: FnMut(Emitted),
     {
-        while let Some(k) = self.emitted_codes.pop_front() {
-            renderer(k.0, k.1)
+        while let Some(e) = self.emitted.pop_front() {
+            renderer(e)
         }
     }
 
@@ -655,13 +951,27 @@ impl<'a> LayerSwitcher<'a> {
             keyset.extend(&l.get_used_keys());
             keyset.extend(&l.on_active_keys);
         }
-        return keyset;
+        keyset
+    }
+
+    /// Parse all layers and return all relative axes that could be emitted
+    /// from them. A virtual pointer is registered when the list is not empty.
+    pub fn get_used_axes(&self) -> Vec<RelativeAxisType> {
+        let mut axes = Vec::new();
+        for l in self.layers {
+            for axis in l.get_used_axes() {
+                if !axes.contains(&axis) {
+                    axes.push(axis);
+                }
+            }
+        }
+        axes
     }
 
-    /// Get list of currently active layers. Needed for tests.
+    /// Get list of currently active layers.
     pub(crate) fn get_active_layers(&self) -> Vec<LayerId> {
         let mut active = Vec::new();
-        for (idx, l) in (&self.layer_stack).into_iter().enumerate() {
+        for (idx, l) in self.layer_stack.iter().enumerate() {
             if l.status != LayerStatus::LayerDisabled && l.status != LayerStatus::LayerPassthrough {
                 active.push(idx as LayerId);
             }
@@ -669,3 +979,10 @@ impl<'a> LayerSwitcher<'a> {
         active
     }
 }
+
+impl Deadline for LayerSwitcher<'_> {
+    /// The earliest timeout of an active layer
+    fn next_deadline(&self) -> Option<Instant> {
+        self.layer_stack.iter().filter_map(|l| l.expires).min()
+    }
+}
@@ -1,8 +1,11 @@
 use std::time::Instant;
 
+use evdev::RelativeAxisType;
+
 use super::keys::KeyGroup;
 
 pub type LayerId = usize;
+pub type LayerGroupId = usize;
 pub type EventCount = u32;
 
 #[derive(Clone, Copy, PartialEq)]
@@ -25,13 +28,56 @@ pub enum LayerStatus {
     /// can trigger key group press and release if the duration of the press was short.
     LayerHoldAndTapKey(KeyCoords, Instant, LayerId), // The key action is retrieved from the keymap
     /// Layer unconditionally disabled, does not participate in key resolution
-    /// And can only be enabled explicitly
+    /// And can only be enabled explicitly (Lenable, Genable)
     LayerDisabled,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct KeyCoords(pub u8, pub u8, pub u8); // Block, row, column
 
+/// Physical orientation of the pad. A keymap is always written for the normal
+/// orientation, the rotated (left-handed) one reverses the key order and swaps
+/// the rotary directions.
+///
+/// ( CCW=10 ROT CW=11 ) [ 0 ][ 1 ][ 2 ][ 6 ]     [ 9 ][    8   ][ 7 ]
+///                      [ 3 ][ 4 ][ 5 ][ _ ]     [ 6 |[ 5 ][ 4 ][ 3 ]
+///                      [ 7 ][    8   ][ 9 ]     | _ ][ 2 ][ 1 ][ 0 ]  ( CCW=10 ROT CW=11 )
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Orientation {
+    #[default]
+    Normal,
+    Rotated,
+}
+
+impl Orientation {
+    /// Number of the last key before the rotary encoder positions
+    const LAST_KEY: u8 = 9;
+    const ROTARY_CCW: u8 = 10;
+    const ROTARY_CW: u8 = 11;
+
+    /// Map a physical key position to the logical position used by the keymap
+    pub fn logical(self, coords: KeyCoords) -> KeyCoords {
+        match (self, coords) {
+            (Orientation::Normal, _) => coords,
+            (Orientation::Rotated, KeyCoords(0, 0, k)) => match k {
+                0..=Self::LAST_KEY => KeyCoords(0, 0, Self::LAST_KEY - k),
+                Self::ROTARY_CCW => KeyCoords(0, 0, Self::ROTARY_CW),
+                Self::ROTARY_CW => KeyCoords(0, 0, Self::ROTARY_CCW),
+                _ => coords,
+            },
+            (Orientation::Rotated, _) => coords,
+        }
+    }
+
+    /// The other orientation
+    pub fn flipped(self) -> Self {
+        match self {
+            Orientation::Normal => Orientation::Rotated,
+            Orientation::Rotated => Orientation::Normal,
+        }
+    }
+}
+
 pub type Keymap = Vec<Vec<Vec<KeymapEvent>>>; // [Block, Row, Col] - > default KeyEvent(None)
 
 #[derive(Clone, PartialEq)]
@@ -42,7 +88,8 @@ pub enum KeymapEvent {
     Inh,
     /// No effect, check other active layers next
     Pass,
-    /// Map key press/release to a keycode
+    /// Map key press/release to a keycode. Mouse buttons (BTN_LEFT, BTN_RIGHT, BTN_MIDDLE)
+    /// can be used too, they are pressed and held the same way as keys.
     Kg(KeyGroup),
     /// If a key is released quickly send first key press/release pair,
     /// but when it is still pressed after the timeout, press the second key
@@ -52,6 +99,18 @@ pub enum KeymapEvent {
     Khl(KeyGroup, LayerId),
     /// A short press for key, long press for activating a tap layer (Ltap)
     Khtl(KeyGroup, LayerId),
+    /// One-shot modifier. A tap presses the keys together with the next emitted
+    /// key and releases them with it. Another tap before that cancels it.
+    Kos(KeyGroup),
+    /// Lockable modifier. A tap behaves like `Kos`, a quick double tap keeps
+    /// the keys pressed until the next tap.
+    Klock(KeyGroup),
+    /// Type a unicode character by clicking the key groups one after another.
+    /// Created using `keys::unicode` for the selected input method.
+    Ku(char, Vec<KeyGroup>),
+    /// Move the pointer (REL_X, REL_Y) or scroll (REL_WHEEL, REL_HWHEEL) by the
+    /// given amount on each press. Meant mostly for the rotary encoder.
+    Mrel(RelativeAxisType, i32),
 
     /// Disable all layers except the base and the parameter
     Lmove(LayerId),
@@ -59,8 +118,16 @@ pub enum KeymapEvent {
     Lactivate(LayerId),
     /// Deactivate a layer
     Ldeactivate(LayerId),
+    /// Activate an inactive layer or deactivate an active one
+    Ltoggle(LayerId),
     /// Permanently disable a layer
     Ldisable(LayerId),
+    /// Enable a disabled layer. The layer stays inactive until activated.
+    Lenable(LayerId),
+    /// Enable all layers belonging to a layer group
+    Genable(LayerGroupId),
+    /// Permanently disable all layers belonging to a layer group
+    Gdisable(LayerGroupId),
     /// Activate layer while the initiating key is kept pressed. Deactivate on release.
     Lhold(LayerId),
     /// Activate layer while the initiating key is kept pressed. Deactivate after one additional key
@@ -72,4 +139,13 @@ pub enum KeymapEvent {
     /// Activate the first mentioned layer on press and deactivate on release. Additionally,
     /// if the elapsed time between press and release was short, send a press+release key event.
     LhtK(LayerId, KeyGroup),
+    /// Keep all layers that are currently active only while a key is held down
+    /// active even after the key is released. They have to be deactivated explicitly.
+    Llock,
+
+    /// Switch the pad to the given orientation. Keys that are being held keep
+    /// the position they were pressed as until released.
+    Oset(Orientation),
+    /// Switch between the normal and the rotated orientation
+    Oflip,
 }
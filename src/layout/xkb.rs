@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use evdev::Key;
+use xkbcommon::xkb;
+
+use super::keys::{key_name, modifier, parse_shortcut, KeyGroup, MODIFIERS};
+
+/// Offset between XKB keycodes and evdev scancodes
+const EVDEV_OFFSET: u32 = 8;
+
+/// Short key names commonly used in application shortcuts mapped to
+/// XKB keysym names
+const ALIASES: &[(&str, &str)] = &[
+    ("esc", "Escape"),
+    ("del", "Delete"),
+    ("ins", "Insert"),
+    ("enter", "Return"),
+    ("backspace", "BackSpace"),
+    ("pgup", "Prior"),
+    ("pgdown", "Next"),
+];
+
+/// Modifiers needed to reach a shift level. Level 2 and 3 are the AltGr
+/// (ISO_Level3_Shift) levels used by most european layouts.
+fn level_modifiers(level: u32) -> Option<Vec<Key>> {
+    match level {
+        0 => Some(vec![]),
+        1 => Some(vec![Key::KEY_LEFTSHIFT]),
+        2 => Some(vec![Key::KEY_RIGHTALT]),
+        3 => Some(vec![Key::KEY_LEFTSHIFT, Key::KEY_RIGHTALT]),
+        _ => None,
+    }
+}
+
+/// Resolves key symbols to evdev scancodes using a compiled XKB keymap,
+/// so keymaps can say "Ctrl+Z" and still work on AZERTY or QWERTZ.
+pub struct XkbLayout {
+    /// Keysym -> (scancode, level)
+    symbols: HashMap<u32, (Key, u32)>,
+
+    /// Scancode -> printable name of its unshifted symbol
+    names: HashMap<Key, String>,
+}
+
+/// Printable name of a keysym, the character it types or the capitalized
+/// keysym name ("Space", "Escape", "F12")
+fn keysym_label(sym: xkb::Keysym) -> String {
+    match char::from_u32(xkb::keysym_to_utf32(sym)) {
+        Some(c) if !c.is_control() && !c.is_whitespace() => c.to_uppercase().collect(),
+        _ => {
+            let name = xkb::keysym_get_name(sym);
+            let mut chars = name.chars();
+            chars.next().map_or(String::new(), |c| c.to_uppercase().chain(chars).collect())
+        }
+    }
+}
+
+impl XkbLayout {
+    /// Compile the XKB keymap for the given layout, variant and options.
+    /// Empty names fall back to the XKB_DEFAULT_* environment variables and
+    /// then to the system defaults.
+    pub fn new(layout: &str, variant: &str, options: Option<String>) -> Option<Self> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            layout,
+            variant,
+            options,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )?;
+
+        let mut symbols: HashMap<u32, (Key, u32)> = HashMap::new();
+        let mut names: HashMap<Key, String> = HashMap::new();
+        keymap.key_for_each(|keymap, keycode| {
+            let code = keycode.raw();
+            if code < EVDEV_OFFSET {
+                return;
+            }
+            let key = Key::new((code - EVDEV_OFFSET) as u16);
+
+            if let Some(sym) = keymap.key_get_syms_by_level(keycode, 0, 0).first() {
+                names.insert(key, keysym_label(*sym));
+            }
+
+            for level in 0..keymap.num_levels_for_key(keycode, 0) {
+                if level_modifiers(level).is_none() {
+                    break;
+                }
+
+                for sym in keymap.key_get_syms_by_level(keycode, 0, level) {
+                    // Prefer the lowest level, then the lowest scancode
+                    let entry = symbols.entry(sym.raw()).or_insert((key, level));
+                    if level < entry.1 {
+                        *entry = (key, level);
+                    }
+                }
+            }
+        });
+
+        Some(Self { symbols, names })
+    }
+
+    /// Find the keys (modifiers first) producing a symbol. The symbol is either
+    /// a modifier name, a single character or an XKB keysym name like "Delete"
+    /// or "F12". Letters match case-insensitively, an uppercase letter
+    /// needs an explicit Shift.
+    pub fn keys(&self, symbol: &str) -> Option<Vec<Key>> {
+        if let Some(key) = modifier(symbol) {
+            return Some(vec![key]);
+        }
+
+        let lower = symbol.to_lowercase();
+        let mut chars = symbol.chars();
+        let keysym = match (chars.next(), chars.next()) {
+            (Some(c), None) => {
+                let c = c.to_lowercase().next().unwrap_or(c);
+                xkb::utf32_to_keysym(c as u32)
+            }
+            _ => {
+                let name = ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == lower)
+                    .map_or(symbol, |(_, name)| name);
+                xkb::keysym_from_name(name, xkb::KEYSYM_CASE_INSENSITIVE)
+            }
+        };
+
+        let (key, level) = self.symbols.get(&keysym.raw())?;
+        let mut keys = level_modifiers(*level)?;
+        keys.push(*key);
+        Some(keys)
+    }
+
+    /// Parse a shortcut like "Ctrl+Z" or "seq: Esc, Esc" (see `parse_shortcut`)
+    /// resolving the key names by symbol
+    pub fn shortcut(&self, s: &str) -> Result<KeyGroup, String> {
+        parse_shortcut(s, &|name| self.keys(name))
+    }
+
+    /// Print a key group using the symbols of this keyboard layout,
+    /// on QWERTZ the KEY_Y scancode prints as "Z"
+    pub fn label(&self, group: &KeyGroup) -> String {
+        group.format_with(&|key| {
+            if let Some((name, _)) = MODIFIERS.iter().find(|(_, k)| *k == key) {
+                return name.to_string();
+            }
+            self.names.get(&key).cloned().unwrap_or_else(|| key_name(key))
+        })
+    }
+}
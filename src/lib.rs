@@ -2,6 +2,12 @@ pub mod virtual_keyboard;
 pub mod xppen_hid;
 pub mod kbd_events;
 pub mod layout;
+pub mod cheatsheet;
+pub mod simulator;
+pub mod timer;
+pub mod pipeline;
+pub mod grab;
+pub mod signals;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -1,53 +1,643 @@
-use std::thread::sleep;
-use std::time::{self, Duration};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap::error::ErrorKind;
+use enumset::EnumSet;
+use hidapi::BusType;
+use log::{debug, error, info, trace, warn, LevelFilter};
+use sd_notify::NotifyState;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use xppen_ack05::layout::switcher::LayerSwitcher;
-use xppen_ack05::xppen_hid::{XpPenAck05, XpPenResult};
-use xppen_ack05::virtual_keyboard::VirtualKeyboard;
-use xppen_ack05::kbd_events::ChangeDetector;
-use xppen_ack05::layout::serialization::load_layout;
+use xppen_ack05::xppen_hid::{self, XpPenAck05, XpPenButtons, XpPenResult};
+use xppen_ack05::grab::SiblingGrab;
+use xppen_ack05::signals::Signals;
+use xppen_ack05::virtual_keyboard::{self, DeviceConfig, VirtualKeyboard};
+use xppen_ack05::kbd_events::HasState;
+use xppen_ack05::kbd_events::debounce::{self, DebounceMode, Debouncer};
+use xppen_ack05::layout::serialization::{load_layout, load_layout_file, Layout};
+use xppen_ack05::layout::xkb::XkbLayout;
+use xppen_ack05::layout::krita::{self, KritaShortcuts};
+use xppen_ack05::layout::types::Orientation;
+use xppen_ack05::cheatsheet;
+use xppen_ack05::simulator::Simulator;
+use xppen_ack05::timer::{self, Clock, Deadline, SystemClock};
+use xppen_ack05::pipeline::{Emitted, Pipeline};
+use xppen_ack05::simulator::scenario::{run_scenario, run_scenarios};
 
+/// Userspace driver of the XP-Pen ACK05 keypad. Without a command it
+/// drives the pad until SIGINT or SIGTERM, SIGHUP resets the layout state.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-fn main() {
-    // Open XPPen ACK05
-    let xppen = XpPenAck05::new();
+    /// Layout file, the built-in layout is used without it
+    #[arg(long, global = true, value_name = "FILE")]
+    layout: Option<PathBuf>,
+
+    /// The pad is used rotated (left-handed)
+    #[arg(long, global = true)]
+    rotated: bool,
+
+    /// XKB keyboard layout the shortcuts are typed on, like "de" or "fr",
+    /// XKB_DEFAULT_LAYOUT is used without it
+    #[arg(long, global = true, value_name = "LAYOUT")]
+    xkb_layout: Option<String>,
+
+    /// XKB layout variant, like "nodeadkeys", XKB_DEFAULT_VARIANT is used without it
+    #[arg(long, global = true, value_name = "VARIANT")]
+    xkb_variant: Option<String>,
+
+    /// XKB options, like "compose:ralt", XKB_DEFAULT_OPTIONS is used without it
+    #[arg(long, global = true, value_name = "OPTIONS")]
+    xkb_options: Option<String>,
+
+    /// Krita shortcut scheme (.shortcuts file) resolving the Krita actions,
+    /// merged over the bundled scheme that only covers the shipped layout
+    #[arg(long, global = true, value_name = "FILE")]
+    krita_scheme: Option<PathBuf>,
+
+    /// Log more, -v logs the pad input, -vv the output as well
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log only warnings, -qq only errors
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+
+    #[command(flatten)]
+    driver: DriverArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write an SVG per layer and a Markdown overview of all layers
+    Cheatsheet {
+        /// Output directory
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Run a script of pad events against the layout without any device
+    Simulate {
+        /// Script file, stdin is read without it
+        script: Option<PathBuf>,
+    },
+    /// List the connected pads, their HID interfaces and whether the driver
+    /// can use them
+    ListDevices,
+    /// Check the layout and the driver options, then exit without opening any device
+    ///
+    /// Parses the options, compiles the XKB keyboard layout, resolves the
+    /// layout with the Krita shortcuts and validates the identity of the
+    /// virtual devices. The pad, /dev/uinput and the grab of the pad's
+    /// keyboard interfaces are not touched, missing permissions only show
+    /// up when the driver runs.
+    Check {
+        #[command(flatten)]
+        driver: DriverArgs,
+    },
+    /// Run scenario files, a directory runs all scenarios in it
+    Test {
+        /// Scenario file or directory
+        path: PathBuf,
+    },
+}
+
+/// Options of the driver itself
+#[derive(Args)]
+struct DriverArgs {
+    /// hidraw node of the pad to use, the first pad is used without it
+    #[arg(long, value_name = "PATH")]
+    device: Option<String>,
+
+    /// Debounce window of all buttons in ms, 0 disables debouncing
+    #[arg(long, value_name = "MS", default_value_t = Millis(debounce::DEFAULT_WINDOW))]
+    debounce: Millis,
+
+    /// eager reports a change immediately, deferred once the button is stable
+    #[arg(long, value_name = "MODE", value_parser = debounce_mode, default_value = "eager")]
+    debounce_mode: DebounceMode,
+
+    /// Debounce window of the worn out buttons 0-9, like 6=30,2=20
+    #[arg(long, value_name = "N=MS", value_delimiter = ',', value_parser = debounce_key)]
+    debounce_key: Vec<(XpPenButtons, Duration)>,
+
+    /// Minimal delay between two output frames in ms
+    #[arg(long, value_name = "MS", value_parser = duration_ms)]
+    frame_delay: Option<Duration>,
+
+    /// Name of the virtual devices
+    #[arg(long, value_name = "NAME")]
+    device_name: Option<String>,
+
+    /// IDs of the virtual devices in hexadecimal
+    #[arg(long, value_name = "VENDOR:PRODUCT[:VERSION]")]
+    device_id: Option<String>,
+
+    /// Bus type of the virtual devices: usb, bluetooth, virtual, ...
+    #[arg(long, value_name = "BUS")]
+    device_bus: Option<String>,
+
+    /// Register all keyboard keys instead of the keys the layout uses
+    #[arg(long)]
+    all_keys: bool,
+
+    /// Leave the pad's stock keyboard interfaces alone
+    #[arg(long)]
+    no_grab: bool,
+}
+
+impl Cli {
+    fn orientation(&self) -> Orientation {
+        if self.rotated {
+            Orientation::Rotated
+        } else {
+            Orientation::Normal
+        }
+    }
+
+    fn log_level(&self) -> LevelFilter {
+        match (self.verbose, self.quiet) {
+            (0, 0) => LevelFilter::Info,
+            (1, _) => LevelFilter::Debug,
+            (_, 0) => LevelFilter::Trace,
+            (_, 1) => LevelFilter::Warn,
+            _ => LevelFilter::Error,
+        }
+    }
+}
+
+/// Parse a duration in ms
+fn duration_ms(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("\"{}\" is not a number of ms", value))
+}
 
-    // XPPen State machine
-    let mut xppen_events = ChangeDetector::new();
+/// Duration option in ms, printable so defaults can come from constants
+#[derive(Clone, Copy)]
+struct Millis(Duration);
 
-    let layout = load_layout("test");
-    let mut layout_runtime = LayerSwitcher::new(&layout);
+impl FromStr for Millis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        duration_ms(s).map(Millis)
+    }
+}
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_millis())
+    }
+}
+
+fn debounce_mode(value: &str) -> Result<DebounceMode, String> {
+    match value {
+        "eager" => Ok(DebounceMode::Eager),
+        "deferred" => Ok(DebounceMode::Deferred),
+        other => Err(format!("unknown mode \"{}\", use eager or deferred", other)),
+    }
+}
+
+/// Parse N=MS, N is the index of a button with state
+fn debounce_key(value: &str) -> Result<(XpPenButtons, Duration), String> {
+    let (key, ms) = value
+        .split_once('=')
+        .ok_or_else(|| format!("\"{}\" is not N=MS", value))?;
+    let button = key
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| EnumSet::<XpPenButtons>::all().iter().filter(|b| b.has_state()).nth(n))
+        .ok_or_else(|| format!("\"{}\" is not a button 0-9", key))?;
+    Ok((button, duration_ms(ms.trim())?))
+}
+
+/// Log to stderr. Under systemd stderr goes to the journal, which adds its
+/// own timestamps and reads the level from the sd-daemon(3) "<N>" prefix.
+/// RUST_LOG overrides the level given on the command line.
+fn init_logging(level: LevelFilter) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+    if env::var_os("JOURNAL_STREAM").is_some() {
+        builder.format(|buf, record| {
+            let priority = match record.level() {
+                log::Level::Error => 3,
+                log::Level::Warn => 4,
+                log::Level::Info => 6,
+                log::Level::Debug | log::Level::Trace => 7,
+            };
+            writeln!(buf, "<{}>{}", priority, record.args())
+        });
+    }
+    builder.init();
+}
+
+/// Log the reason the driver cannot continue and exit
+fn exit_with(message: &str) -> ! {
+    error!("{}", message);
+    process::exit(1);
+}
+
+/// Exit with a message about an invalid option value
+fn invalid_option(message: &str) -> ! {
+    Cli::command().error(ErrorKind::InvalidValue, message).exit()
+}
+
+/// Debounce filter of the pad buttons
+fn debouncer(args: &DriverArgs) -> Debouncer<XpPenButtons> {
+    let mut debouncer = Debouncer::new(args.debounce_mode, args.debounce.0);
+    for (button, window) in &args.debounce_key {
+        debouncer.set_window(*button, *window);
+    }
+    debouncer
+}
+
+/// Identity of the virtual devices
+fn device_config(args: &DriverArgs) -> DeviceConfig {
+    let mut config = DeviceConfig {
+        all_keys: args.all_keys,
+        ..Default::default()
+    };
+    if let Some(name) = &args.device_name {
+        config.name = name.clone();
+    }
+
+    let result = args
+        .device_id
+        .as_deref()
+        .map_or(Ok(()), |id| config.set_id(id))
+        .and_then(|_| args.device_bus.as_deref().map_or(Ok(()), |bus| config.set_bus(bus)));
+    if let Err(e) = result {
+        invalid_option(&e);
+    }
+
+    config
+}
+
+/// Keyboard layout used to resolve key symbols, selected with the
+/// --xkb-* options or the XKB_DEFAULT_* variables
+fn keyboard(cli: &Cli) -> XkbLayout {
+    let layout = cli.xkb_layout.as_deref().unwrap_or_default();
+    let variant = cli.xkb_variant.as_deref().unwrap_or_default();
+    XkbLayout::new(layout, variant, cli.xkb_options.clone()).unwrap_or_else(|| {
+        let layout = match layout {
+            "" => env::var("XKB_DEFAULT_LAYOUT").unwrap_or_default(),
+            layout => layout.to_string(),
+        };
+        exit_with(&format!(
+            "Cannot compile the XKB keyboard layout \"{}\", check --xkb-layout and --xkb-variant",
+            layout
+        ))
+    })
+}
+
+/// Load the layout given by --layout, or the built-in one
+fn load(cli: &Cli, keyboard: &XkbLayout) -> Layout {
+    // Krita actions resolve to the shortcuts configured in Krita
+    let mut scheme = KritaShortcuts::default_scheme();
+    if let Some(path) = &cli.krita_scheme {
+        scheme.merge_file(path).unwrap_or_else(|e| {
+            exit_with(&format!("Cannot load the Krita scheme {}: {}", path.display(), e))
+        });
+    }
+    let mut krita_shortcuts = scheme.clone();
+    let user_config = krita::user_config_path().filter(|p| p.exists());
+    if let Some(path) = &user_config {
+        match krita_shortcuts.merge_file(path) {
+            Ok(()) => info!("Using Krita shortcuts from {}", path.display()),
+            Err(e) => warn!("Ignoring the Krita shortcuts from {}: {}", path.display(), e),
+        }
+    }
+
+    let layout = |krita_shortcuts: &KritaShortcuts| match &cli.layout {
+        Some(path) => load_layout_file(path, keyboard, krita_shortcuts)
+            .map_err(|e| format!("Cannot load the layout: {}", e)),
+        None => load_layout("test", keyboard, krita_shortcuts)
+            .map_err(|e| format!("Cannot load the built-in layout: {}", e)),
+    };
+
+    match layout(&krita_shortcuts) {
+        Ok(layout) => layout,
+        // A shortcut the user assigned in Krita may not resolve,
+        // the scheme alone still gives a usable pad
+        Err(e) if krita_shortcuts != scheme => match layout(&scheme) {
+            Ok(layout) => {
+                warn!("{}, using the Krita scheme shortcuts only", e);
+                layout
+            }
+            Err(_) => exit_with(&e),
+        },
+        Err(e) => exit_with(&e),
+    }
+}
+
+/// Write an SVG per layer and a Markdown overview of all layers
+fn cheatsheet(cli: &Cli, dir: &Path) {
+    let keyboard = keyboard(cli);
+    let layout = load(cli, &keyboard);
+    fs::create_dir_all(dir)
+        .unwrap_or_else(|e| exit_with(&format!("Cannot create {}: {}", dir.display(), e)));
+
+    let write = |path: PathBuf, content: String| {
+        fs::write(&path, content)
+            .unwrap_or_else(|e| exit_with(&format!("Cannot write {}: {}", path.display(), e)));
+        info!("Written {}", path.display());
+    };
+
+    for idx in 0..layout.layers.len() {
+        write(
+            dir.join(format!("layer-{}.svg", idx)),
+            cheatsheet::render_svg(&layout.layers, idx, cli.orientation(), &keyboard),
+        );
+    }
+    write(dir.join("layout.md"), cheatsheet::render_markdown(&layout.layers, &keyboard));
+}
+
+/// Run a script of pad events against the layout without any device,
+/// stdin is read when no script is given
+fn simulate(cli: &Cli, script: Option<&Path>) {
+    let layout = load(cli, &keyboard(cli));
+    let mut simulator = Simulator::new(&layout, cli.orientation());
+    let mut out = io::stdout();
+
+    let result = match script {
+        Some(path) => fs::File::open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+            .and_then(|f| simulator.run(BufReader::new(f), &mut out)),
+        None => simulator.run(io::stdin().lock(), &mut out),
+    };
+
+    if let Err(e) = result {
+        error!("Simulation failed: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Run scenario files, a directory runs all scenarios in it
+fn test(path: &Path) {
+    let results = if path.is_dir() {
+        run_scenarios(path).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(2);
+        })
+    } else {
+        vec![(path.to_path_buf(), run_scenario(path))]
+    };
+
+    let mut failed = 0;
+    for (path, result) in &results {
+        match result {
+            Ok(()) => println!("ok      {}", path.display()),
+            Err(e) => {
+                failed += 1;
+                println!("FAILED  {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Print the HID interfaces of the connected pads and whether the driver
+/// can use them, the keys interface is the one --device selects.
+/// Exits with 1 when the driver would not start.
+fn list_devices() {
+    let interfaces = xppen_hid::list().unwrap_or_else(|e| exit_with(&e));
+
+    // Interfaces of one pad share the USB device, the serial number
+    // cannot tell pads apart as the ACK05 reports an empty one.
+    // Interfaces outside of USB are listed on their own.
+    let pad = |iface: &xppen_hid::PadInterface| {
+        iface.usb_device.clone().unwrap_or_else(|| PathBuf::from(&iface.path))
+    };
+    let mut pads: Vec<PathBuf> = Vec::new();
+    for iface in &interfaces {
+        if !pads.contains(&pad(iface)) {
+            pads.push(pad(iface));
+        }
+    }
+
+    for p in &pads {
+        let ifaces: Vec<_> = interfaces.iter().filter(|i| pad(i) == *p).collect();
+        let location = match &ifaces[0].usb_device {
+            Some(usb) => format!("USB {}", usb.file_name().unwrap_or_default().to_string_lossy()),
+            None => format!("{:?}", ifaces[0].bus),
+        };
+        let serial = match ifaces[0].serial.as_str() {
+            "" => String::new(),
+            serial => format!(" serial \"{}\"", serial),
+        };
+        println!("XP-Pen ACK05 {:04x}:{:04x} on {}{}", xppen_hid::VID, xppen_hid::PID, location, serial);
+
+        for iface in ifaces {
+            let access = match &iface.access {
+                Ok(()) => "ok".to_string(),
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+                Err(e) => e.to_string(),
+            };
+            println!(
+                "  {:<14} interface {}  usage page {:04x}  usage {:04x}  {:<4}  {}",
+                iface.path,
+                iface.interface,
+                iface.usage_page,
+                iface.usage,
+                if iface.is_keys() { "keys" } else { "" },
+                access
+            );
+        }
+    }
+
+    let uinput = virtual_keyboard::check_uinput();
+    println!("{:<16} {}", virtual_keyboard::UINPUT_PATH, if uinput.is_ok() { "ok" } else { "not usable" });
+
+    // Explain what keeps the driver from starting
+    let mut problems = Vec::new();
+    let keys: Vec<_> = interfaces.iter().filter(|i| i.is_keys()).collect();
+    if keys.is_empty() {
+        problems.push("No XP-Pen ACK05 found, check that it is connected using USB".to_string());
+    } else if let Some(iface) = keys.iter().find(|i| i.access.is_ok()) {
+        if matches!(iface.bus, BusType::Bluetooth) {
+            problems.push(
+                "Bluetooth connection is currently not supported, connect the pad using USB".to_string(),
+            );
+        }
+    } else if let Err(e) = &keys[0].access {
+        problems.push(xppen_hid::access_error(&keys[0].path, e));
+    }
+    problems.extend(uinput.err());
+
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// Load the layout and validate the options without touching any device,
+/// see `Command::Check`
+fn check(cli: &Cli, driver: &DriverArgs) {
+    let layout = load(cli, &keyboard(cli));
+    let config = device_config(driver);
+
+    let mut switcher = layout.switcher();
+    switcher.set_orientation(cli.orientation());
+    info!(
+        "{}: {} layers, {} keys, virtual device \"{}\"",
+        cli.layout.as_deref().map_or("built-in layout".into(), |p| p.display().to_string()),
+        layout.layers.len(),
+        switcher.get_used_keys().len(),
+        config.name
+    );
+}
+
+/// Log an emitted event and queue it to the virtual devices
+fn output(kbd: &mut VirtualKeyboard, e: Emitted) {
+    match e {
+        Emitted::Key(k, s) => trace!("Output > {:?} pressed {}", k, s),
+        Emitted::Motion(a, v) => trace!("Output > {:?} moved {}", a, v),
+    }
+    kbd.push(e);
+}
+
+/// Drive the pad until SIGINT or SIGTERM
+fn run(cli: &Cli) {
+    let args = &cli.driver;
+
+    // Open XPPen ACK05
+    let xppen = XpPenAck05::open(args.device.as_deref()).unwrap_or_else(|e| exit_with(&e));
+
+    let layout = load(cli, &keyboard(cli));
+    let mut layout_runtime = layout.switcher();
+    layout_runtime.set_orientation(cli.orientation());
     layout_runtime.start();
 
     // Create a virtual keyboard
-    let mut kbd = VirtualKeyboard::new(layout_runtime.get_used_keys());
+    let config = device_config(args);
+    let mut kbd = VirtualKeyboard::new(
+        &config,
+        layout_runtime.get_used_keys(),
+        layout_runtime.get_used_axes(),
+    )
+    .unwrap_or_else(|e| exit_with(&e));
+    if let Some(delay) = args.frame_delay {
+        kbd.set_frame_delay(delay);
+    }
+
+    kbd.release_on_panic();
+
+    // Keep the pad's stock keyboard interfaces from reaching the desktop,
+    // --no-grab leaves them alone. The grab is released on exit.
+    let grab = if args.no_grab {
+        None
+    } else {
+        Some(SiblingGrab::grab(xppen.path(), kbd.nodes()))
+    };
 
-    // Wait for a HID event when reading from XP Pen (= block)
-    xppen.set_blocking();
+    // XPPen State machine, fed with the debounced button state
+    let mut pipeline = Pipeline::new(SystemClock, debouncer(args), layout_runtime);
 
-    loop {
-        // Read state data from device
-        // When any button is pressed use read timeout so the long press can be
-        // analyzed in between messages.
-        let result = xppen.read(!xppen_events.has_short_pressed());
-        //println!("{:?}", result);
+    // SIGINT and SIGTERM stop the driver, SIGHUP resets the layout state.
+    // The keys that are down get released in both cases. The signals wake up
+    // the wait for the next report.
+    let terminate = Arc::new(AtomicBool::new(false));
+    let reset = Arc::new(AtomicBool::new(false));
+    let signals = Signals::new()
+        .and_then(|signals| {
+            for signal in [SIGINT, SIGTERM] {
+                signals.register(signal, &terminate)?;
+            }
+            signals.register(SIGHUP, &reset)?;
+            Ok(signals)
+        })
+        .unwrap_or_else(|e| exit_with(&format!("Cannot handle signals: {}", e)));
 
-        if let XpPenResult::Keys(buttons) = result {
+    // Tell systemd the virtual devices exist, does nothing outside of systemd
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+    info!("Ready");
+
+    let mut failure = None;
+    while !terminate.load(Ordering::Relaxed) {
+        // Wait for a report from the device, or until the earliest deadline of
+        // the components that change with time: the long press detection,
+        // the debounce window and the delayed output frames. Without deadlines
+        // the read blocks.
+        let deadline = timer::earliest([pipeline.next_deadline(), kbd.next_deadline()]);
+        let result = xppen.read(timer::timeout(deadline, SystemClock.now()), &signals);
+
+        if reset.swap(false, Ordering::Relaxed) {
+            info!("Resetting the layout state");
+            pipeline.reset();
+            kbd.release_all();
+        }
+
+        let bounces = pipeline.debouncer().total_bounces();
+        match result {
             // Compute state changes
-            xppen_events.analyze(buttons, time::Instant::now());
-        } else {
-            xppen_events.tick(time::Instant::now());
+            XpPenResult::Keys(buttons) => pipeline.report(buttons),
+            // The pad was unplugged, leave the loop to release the keys
+            XpPenResult::Error(e) => {
+                failure = Some(format!("Cannot read from the device: {}", e));
+                break;
+            }
+            _ => pipeline.tick(),
+        }
+
+        let total_bounces = pipeline.debouncer().total_bounces();
+        if total_bounces > bounces {
+            debug!("Debounce: {} bounces filtered so far", total_bounces);
         }
 
-        // Emit virtual keys
-        while let Some(ev) = xppen_events.next() {
-            println!("Input: {:?}", ev);
-            layout_runtime.process_keyevent(ev, time::Instant::now());
-            layout_runtime.render(|k, s| {
-                println!("Output > {:?} pressed {}", k, s);
-                kbd.emit_key(k, s);
-                sleep(Duration::from_millis(2));
-            });
+        // Emit virtual keys, including the keys released by layer timeouts
+        while let Some(ev) = pipeline.next_input() {
+            debug!("Input: {:?}", ev);
+            pipeline.process(ev, |e| output(&mut kbd, e));
+        }
+        pipeline.render(|e| output(&mut kbd, e));
+        // A frame may be half written, start over from a clean state
+        if let Err(e) = kbd.flush(SystemClock.now()) {
+            error!("Cannot emit the virtual keys: {}", e);
+            pipeline.reset();
+            kbd.release_all();
         }
     }
+
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+    info!("Stopping");
+
+    // Release the keys that are down and the grab before exiting
+    drop(kbd);
+    drop(grab);
+    if let Some(e) = failure {
+        exit_with(&e);
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    init_logging(cli.log_level());
+
+    match &cli.command {
+        Some(Command::Cheatsheet { dir }) => cheatsheet(&cli, dir),
+        Some(Command::Simulate { script }) => simulate(&cli, script.as_deref()),
+        Some(Command::ListDevices) => list_devices(),
+        Some(Command::Check { driver }) => check(&cli, driver),
+        Some(Command::Test { path }) => test(path),
+        None => run(&cli),
+    }
 }
@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+use enumset::EnumSet;
+
+use crate::kbd_events::debounce::Debouncer;
+use crate::kbd_events::{ChangeDetector, KeyStateChange};
+pub use crate::layout::switcher::Emitted;
+use crate::layout::switcher::LayerSwitcher;
+use crate::timer::{self, Clock, Deadline};
+use crate::xppen_hid::XpPenButtons;
+
+/// The input pipeline from the device reports to the emitted keys:
+/// debouncer -> change detector -> layer switcher.
+///
+/// The clock is read once per device report or tick and the same time is
+/// passed to all the components. The device loop uses the system clock,
+/// tests and the simulator a manual clock.
+pub struct Pipeline<'a, C: Clock> {
+    clock: C,
+    debouncer: Debouncer<XpPenButtons>,
+    detector: ChangeDetector<XpPenButtons>,
+    switcher: LayerSwitcher<'a>,
+    /// Time of the last report or tick
+    now: Instant,
+}
+
+impl<'a, C: Clock> Pipeline<'a, C> {
+    /// Build the pipeline around a started layer switcher
+    pub fn new(clock: C, debouncer: Debouncer<XpPenButtons>, switcher: LayerSwitcher<'a>) -> Self {
+        let now = clock.now();
+        Self {
+            clock,
+            debouncer,
+            detector: ChangeDetector::new(),
+            switcher,
+            now,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn debouncer(&self) -> &Debouncer<XpPenButtons> {
+        &self.debouncer
+    }
+
+    pub fn switcher(&self) -> &LayerSwitcher<'a> {
+        &self.switcher
+    }
+
+    pub fn switcher_mut(&mut self) -> &mut LayerSwitcher<'a> {
+        &mut self.switcher
+    }
+
+    /// Use the same long press threshold in the change detector
+    /// and the layer switcher
+    pub fn set_long_press(&mut self, long_press: Duration) {
+        self.detector.set_long_press(long_press);
+        self.switcher.set_hold_threshold(long_press);
+    }
+
+    /// Forget all held and debounced keys and restart the layer switcher from
+    /// the initial layer state. The output has to release all its keys as well.
+    pub fn reset(&mut self) {
+        self.debouncer.reset();
+        let long_press = self.detector.get_long_press();
+        self.detector = ChangeDetector::new();
+        self.detector.set_long_press(long_press);
+        self.switcher.start();
+    }
+
+    /// Time of the last report or tick
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Time left until the next deadline, None when the pipeline only
+    /// waits for input
+    pub fn timeout(&self) -> Option<Duration> {
+        timer::timeout(self.next_deadline(), self.clock.now())
+    }
+
+    /// Process a device report
+    pub fn report(&mut self, buttons: EnumSet<XpPenButtons>) {
+        self.now = self.clock.now();
+        let buttons = self.debouncer.update(buttons, self.now);
+        self.detector.analyze(buttons, self.now);
+    }
+
+    /// Time passed without a report, apply the due debounced changes,
+    /// long presses and layer timeouts
+    pub fn tick(&mut self) {
+        self.now = self.clock.now();
+        if let Some(buttons) = self.debouncer.poll(self.now) {
+            self.detector.analyze(buttons, self.now);
+        } else {
+            self.detector.tick(self.now);
+        }
+        self.switcher.tick(self.now);
+    }
+
+    /// Take the next detected key state change
+    pub fn next_input(&mut self) -> Option<KeyStateChange<XpPenButtons>> {
+        self.detector.next()
+    }
+
+    /// Pass a key state change to the layer switcher and emit its output
+    pub fn process<F: FnMut(Emitted)>(&mut self, ev: KeyStateChange<XpPenButtons>, emit: F) {
+        self.switcher.process_keyevent(ev, self.now);
+        self.render(emit);
+    }
+
+    /// Emit the output of the layer switcher that was not emitted yet,
+    /// like the keys released by a layer timeout
+    pub fn render<F: FnMut(Emitted)>(&mut self, mut emit: F) {
+        self.switcher.render(&mut emit);
+    }
+
+    /// Process all detected key state changes and emit all output
+    pub fn process_all<F: FnMut(Emitted)>(&mut self, mut emit: F) {
+        while let Some(ev) = self.next_input() {
+            self.process(ev, &mut emit);
+        }
+        self.render(emit);
+    }
+}
+
+impl<C: Clock> Deadline for Pipeline<'_, C> {
+    fn next_deadline(&self) -> Option<Instant> {
+        timer::earliest([
+            self.detector.next_deadline(),
+            self.debouncer.next_deadline(),
+            self.switcher.next_deadline(),
+        ])
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{self, Read};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::raw::c_int;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Result of `Signals::wait`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wake {
+    /// The device has data to read
+    Readable,
+    /// A signal arrived, the flags tell which one
+    Signal,
+    Timeout,
+}
+
+/// Signals handled by the device loop. A signal sets its flag and wakes up
+/// `wait` through a self-pipe. The handlers restart interrupted system calls
+/// (SA_RESTART), a blocking read of the device would not see the signal
+/// until the next report otherwise.
+pub struct Signals {
+    read: UnixStream,
+    write: UnixStream,
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        let (read, write) = UnixStream::pair()?;
+        read.set_nonblocking(true)?;
+        write.set_nonblocking(true)?;
+        Ok(Self { read, write })
+    }
+
+    /// Set `flag` and wake up `wait` when `signal` arrives
+    pub fn register(&self, signal: c_int, flag: &Arc<AtomicBool>) -> io::Result<()> {
+        // The flag is set before the wake up, the handlers run in this order
+        signal_hook::flag::register(signal, Arc::clone(flag))?;
+        signal_hook::low_level::pipe::register(signal, self.write.try_clone()?)?;
+        Ok(())
+    }
+
+    /// Wait until the device is readable, a signal arrives or the timeout
+    /// elapses, None waits forever. The timeout is rounded up to whole
+    /// milliseconds, so a deadline is never woken up too early.
+    /// Errors and hangups of the device, like an unplugged pad, are errors.
+    pub fn wait(&self, device: BorrowedFd<'_>, timeout: Option<Duration>) -> io::Result<Wake> {
+        let timeout = timeout.map_or(-1, |t| {
+            c_int::try_from(t.as_nanos().div_ceil(1_000_000)).unwrap_or(c_int::MAX)
+        });
+        let mut fds = [
+            libc::pollfd { fd: device.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: self.read.as_fd().as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+
+        // SAFETY: fds is a valid array of pollfd structures for the whole call
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            // A signal without a wake up pipe, like one sent to this thread only
+            return if e.kind() == io::ErrorKind::Interrupted { Ok(Wake::Signal) } else { Err(e) };
+        }
+
+        if fds[1].revents != 0 {
+            self.drain();
+            return Ok(Wake::Signal);
+        }
+        if fds[0].revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            return Err(io::Error::other("device disconnected"));
+        }
+        if fds[0].revents & libc::POLLIN != 0 {
+            return Ok(Wake::Readable);
+        }
+        Ok(Wake::Timeout)
+    }
+
+    /// Consume the pending wake ups
+    fn drain(&self) {
+        let mut buf = [0u8; 64];
+        while matches!((&self.read).read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
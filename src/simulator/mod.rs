@@ -0,0 +1,183 @@
+pub mod scenario;
+
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+
+use enumset::EnumSet;
+
+use crate::kbd_events::debounce::{DebounceMode, Debouncer};
+use crate::layout::serialization::Layout;
+use crate::layout::types::{LayerId, Orientation};
+use crate::pipeline::Pipeline;
+use crate::timer::{Clock, Deadline, ManualClock};
+use crate::xppen_hid::XpPenButtons;
+
+pub use crate::pipeline::Emitted;
+
+/// Rotary encoder pulses, the button names do not match the direction
+/// the keymap uses (CCW=10, CW=11)
+const ROTARY_CCW: XpPenButtons = XpPenButtons::XpRoCW;
+const ROTARY_CW: XpPenButtons = XpPenButtons::XpRoCCW;
+
+/// Feeds scripted pad events through the input pipeline on virtual time,
+/// no device is opened and no keys are sent to the OS. Scripted reports
+/// are clean, so debouncing is disabled.
+///
+/// Script commands, one per line:
+///   press N, release N, tap N  - key N (0-9) down, up or both
+///   cw [count], ccw [count]    - rotary encoder pulses
+///   wait MS                    - let the time pass
+///   # comment
+pub struct Simulator<'a> {
+    pipeline: Pipeline<'a, ManualClock>,
+    /// Buttons currently held down
+    buttons: EnumSet<XpPenButtons>,
+    start: Instant,
+    /// Active layers after the last command
+    active_layers: Vec<LayerId>,
+}
+
+impl<'a> Simulator<'a> {
+    pub fn new(layout: &'a Layout, orientation: Orientation) -> Self {
+        let mut switcher = layout.switcher();
+        switcher.set_orientation(orientation);
+        switcher.start();
+        let active_layers = switcher.get_active_layers();
+
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let debouncer = Debouncer::new(DebounceMode::Eager, Duration::ZERO);
+
+        Self {
+            pipeline: Pipeline::new(clock, debouncer, switcher),
+            buttons: EnumSet::empty(),
+            start,
+            active_layers,
+        }
+    }
+
+    pub fn get_active_layers(&self) -> &[LayerId] {
+        &self.active_layers
+    }
+
+    /// Time elapsed since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.pipeline.clock().now() - self.start
+    }
+
+    fn report(&mut self, buttons: EnumSet<XpPenButtons>, emitted: &mut Vec<Emitted>) {
+        self.pipeline.report(buttons);
+        self.pipeline.process_all(|e| emitted.push(e));
+    }
+
+    /// Let the time pass until `duration` elapses, the pipeline is
+    /// ticked at its deadlines the same way the device loop does it
+    pub fn wait(&mut self, duration: Duration) -> Vec<Emitted> {
+        let mut emitted = Vec::new();
+        let end = self.pipeline.clock().now() + duration;
+        while let Some(deadline) = self.pipeline.next_deadline().filter(|d| *d <= end) {
+            self.pipeline.clock().set(deadline);
+            self.pipeline.tick();
+            self.pipeline.process_all(|e| emitted.push(e));
+        }
+        self.pipeline.clock().set(end);
+        emitted
+    }
+
+    /// Execute one script command and return what the layer switcher emitted
+    pub fn execute(&mut self, line: &str) -> Result<Vec<Emitted>, String> {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+        let Some(cmd) = words.next() else {
+            return Ok(vec![]);
+        };
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(format!("Too many arguments in \"{}\"", line));
+        }
+
+        let number = |what: &str| -> Result<u64, String> {
+            arg.ok_or_else(|| format!("\"{}\" needs {}", cmd, what))?
+                .parse()
+                .map_err(|_| format!("Invalid {} in \"{}\"", what, line))
+        };
+        let key = || -> Result<XpPenButtons, String> {
+            let n = number("a key number")?;
+            EnumSet::<XpPenButtons>::all()
+                .iter()
+                .filter(|b| *b != ROTARY_CCW && *b != ROTARY_CW)
+                .nth(n as usize)
+                .ok_or_else(|| format!("There is no key {}", n))
+        };
+
+        let mut emitted = Vec::new();
+        match cmd {
+            "press" => {
+                self.buttons |= key()?;
+                self.report(self.buttons, &mut emitted);
+            }
+            "release" => {
+                self.buttons -= key()?;
+                self.report(self.buttons, &mut emitted);
+            }
+            "tap" => {
+                let k = key()?;
+                self.report(self.buttons | k, &mut emitted);
+                self.report(self.buttons, &mut emitted);
+            }
+            "cw" | "ccw" => {
+                let pulse = if cmd == "cw" { ROTARY_CW } else { ROTARY_CCW };
+                let count = if arg.is_some() { number("a pulse count")? } else { 1 };
+                for _ in 0..count {
+                    self.report(self.buttons | pulse, &mut emitted);
+                    self.report(self.buttons, &mut emitted);
+                }
+            }
+            "wait" => emitted = self.wait(Duration::from_millis(number("a duration")?)),
+            _ => return Err(format!("Unknown command \"{}\"", cmd)),
+        }
+
+        self.active_layers = self.pipeline.switcher().get_active_layers();
+        Ok(emitted)
+    }
+
+    /// Execute one script line and write the results to `out`
+    pub fn step<W: Write>(&mut self, line: &str, out: &mut W) -> Result<(), String> {
+        let command = line.split('#').next().unwrap_or("").trim();
+        if command.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(out, "[{:>5} ms] {}", self.elapsed().as_millis(), command).map_err(|e| e.to_string())?;
+
+        let active_layers = self.active_layers.clone();
+        for ev in self.execute(command)? {
+            match ev {
+                Emitted::Key(k, s) => writeln!(out, "  > {:?} {}", k, if s { "pressed" } else { "released" }),
+                Emitted::Motion(a, v) => writeln!(out, "  > {:?} moved {}", a, v),
+            }
+            .map_err(|e| e.to_string())?;
+        }
+
+        if active_layers != self.active_layers {
+            writeln!(out, "  layers {:?}", self.active_layers).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute a script, errors are reported and the script continues
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> Result<(), String> {
+        writeln!(out, "  layers {:?}", self.active_layers).map_err(|e| e.to_string())?;
+
+        for (idx, line) in input.lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            if let Err(e) = self.step(&line, out) {
+                writeln!(out, "  error on line {}: {}", idx + 1, e).map_err(|e| e.to_string())?;
+            }
+            out.flush().map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use evdev::{Key, RelativeAxisType};
+use toml::{Table, Value};
+
+use crate::layout::krita::KritaShortcuts;
+use crate::layout::serialization::{load_layout, load_layout_file, parse_layout_table, Layout};
+use crate::layout::types::{LayerId, Orientation};
+use crate::layout::xkb::XkbLayout;
+
+use super::{Emitted, Simulator};
+
+/// Layout name referring to the built-in Krita layout
+const BUILTIN_LAYOUT: &str = "builtin";
+
+/// Top-level keys of a scenario file, the rest belongs to an inline layout
+const SCENARIO_KEYS: &[&str] = &["layout", "keyboard", "orientation", "step"];
+
+/// A scenario file describes a layout, a list of timed pad events and the
+/// expected output of each step:
+///
+/// ```toml
+/// layout = "builtin"    # "builtin", a layout file relative to the scenario
+///                       # or omitted when [[layer]] tables follow
+/// keyboard = "us"       # XKB layout used to resolve symbols (default "us")
+/// orientation = "normal"
+///
+/// [[step]]
+/// at = 0                # optional time of the step in ms
+/// input = "press 4"     # simulator command
+/// keys = [["KEY_LEFTCTRL", true]]
+/// motion = [["REL_WHEEL", 1]]
+/// layers = [0, 1]       # optional, active layers after the step
+/// ```
+///
+/// Keys and motion are always compared, an omitted list means nothing
+/// is emitted. Shortcuts resolve using the default Krita scheme only,
+/// so scenarios do not depend on the local Krita configuration.
+pub fn run_scenario(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let doc: Table = content.parse().map_err(|e: toml::de::Error| e.to_string())?;
+
+    let keyboard_name = match doc.get("keyboard") {
+        None => "us",
+        Some(Value::String(s)) => s,
+        Some(v) => return Err(format!("\"keyboard\" must be a string, found {}", v)),
+    };
+    let keyboard = XkbLayout::new(keyboard_name, "", None)
+        .ok_or_else(|| format!("Cannot compile the XKB layout \"{}\"", keyboard_name))?;
+    let krita = KritaShortcuts::default_scheme();
+
+    let mut inline = doc.clone();
+    inline.retain(|name, _| !SCENARIO_KEYS.contains(&name));
+    if let (Some(_), Some(name)) = (doc.get("layout"), inline.keys().next()) {
+        return Err(format!("Unknown scenario key \"{}\"", name));
+    }
+
+    let layout: Layout = match doc.get("layout") {
+        None => parse_layout_table(&inline, &keyboard, &krita)?,
+        Some(Value::String(s)) if s == BUILTIN_LAYOUT => load_layout("test", &keyboard, &krita)?,
+        Some(Value::String(s)) => {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            load_layout_file(&dir.join(s), &keyboard, &krita)?
+        }
+        Some(v) => return Err(format!("\"layout\" must be a string, found {}", v)),
+    };
+
+    let orientation = match doc.get("orientation").and_then(Value::as_str) {
+        None | Some("normal") => Orientation::Normal,
+        Some("rotated") => Orientation::Rotated,
+        Some(o) => return Err(format!("Unknown orientation \"{}\"", o)),
+    };
+
+    let steps = match doc.get("step") {
+        Some(Value::Array(steps)) => steps,
+        _ => return Err("The scenario needs at least one [[step]]".to_string()),
+    };
+
+    let mut simulator = Simulator::new(&layout, orientation);
+    for (idx, step) in steps.iter().enumerate() {
+        let step = step.as_table().ok_or(format!("step {}: not a table", idx))?;
+        run_step(&mut simulator, step).map_err(|e| format!("step {}: {}", idx, e))?;
+    }
+
+    Ok(())
+}
+
+fn run_step(simulator: &mut Simulator, step: &Table) -> Result<(), String> {
+    let mut emitted = Vec::new();
+
+    if let Some(at) = step.get("at") {
+        let at = at
+            .as_integer()
+            .filter(|at| *at >= 0)
+            .map(|at| Duration::from_millis(at as u64))
+            .ok_or(format!("\"at\" must be a time in ms, found {}", at))?;
+        if at < simulator.elapsed() {
+            return Err(format!("\"at\" {:?} is in the past", at));
+        }
+        emitted.extend(simulator.wait(at - simulator.elapsed()));
+    }
+
+    if let Some(input) = step.get("input") {
+        let input = input.as_str().ok_or(format!("\"input\" must be a string, found {}", input))?;
+        emitted.extend(simulator.execute(input)?);
+    }
+
+    let mut expected = Vec::new();
+    for (idx, item) in array(step, "keys")?.iter().enumerate() {
+        let (name, state) = match item.as_array().map(Vec::as_slice) {
+            Some([Value::String(name), Value::Boolean(state)]) => (name, *state),
+            _ => return Err(format!("keys {}: expected [\"KEY_NAME\", true|false], found {}", idx, item)),
+        };
+        let key = Key::from_str(name).map_err(|_| format!("keys {}: unknown key \"{}\"", idx, name))?;
+        expected.push(Emitted::Key(key, state));
+    }
+    for (idx, item) in array(step, "motion")?.iter().enumerate() {
+        let (name, value) = match item.as_array().map(Vec::as_slice) {
+            Some([Value::String(name), Value::Integer(value)]) => (name, *value as i32),
+            _ => return Err(format!("motion {}: expected [\"REL_AXIS\", value], found {}", idx, item)),
+        };
+        let axis = RelativeAxisType::from_str(name).map_err(|_| format!("motion {}: unknown axis \"{}\"", idx, name))?;
+        expected.push(Emitted::Motion(axis, value));
+    }
+
+    // Keys and motion are checked separately, their relative order is not tracked
+    let (keys, motion): (Vec<_>, Vec<_>) = emitted.into_iter().partition(|e| matches!(e, Emitted::Key(..)));
+    let (exp_keys, exp_motion): (Vec<_>, Vec<_>) = expected.into_iter().partition(|e| matches!(e, Emitted::Key(..)));
+    if keys != exp_keys {
+        return Err(format!("emitted keys {:?}, expected {:?}", keys, exp_keys));
+    }
+    if motion != exp_motion {
+        return Err(format!("emitted motion {:?}, expected {:?}", motion, exp_motion));
+    }
+
+    if step.contains_key("layers") {
+        let layers = array(step, "layers")?
+            .iter()
+            .map(|l| l.as_integer().map(|l| l as LayerId).ok_or(format!("Invalid layer {}", l)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if simulator.get_active_layers() != layers.as_slice() {
+            return Err(format!("active layers {:?}, expected {:?}", simulator.get_active_layers(), layers));
+        }
+    }
+
+    Ok(())
+}
+
+fn array<'t>(table: &'t Table, name: &str) -> Result<&'t [Value], String> {
+    match table.get(name) {
+        None => Ok(&[]),
+        Some(Value::Array(a)) => Ok(a),
+        Some(v) => Err(format!("\"{}\" must be an array, found {}", name, v)),
+    }
+}
+
+/// Scenario file and its result
+pub type ScenarioResult = (PathBuf, Result<(), String>);
+
+/// Run all `.toml` scenarios in a directory, sorted by name
+pub fn run_scenarios(dir: &Path) -> Result<Vec<ScenarioResult>, String> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|p| {
+            let result = run_scenario(&p);
+            (p, result)
+        })
+        .collect())
+}
@@ -1,11 +1,27 @@
-use evdev::Key;
+use std::time::Duration;
 
-use crate::kbd_events::KeyStateChange;
+use enumset::EnumSet;
+use evdev::{Key, RelativeAxisType};
+
+use crate::kbd_events::{ChangeDetector, KeyStateChange};
+use crate::kbd_events::debounce::{DebounceMode, Debouncer};
 use crate::layout::layer::Layer;
-use crate::layout::types::KeyCoords;
-use crate::layout::switcher::LayerSwitcher;
-use crate::layout::types::KeymapEvent::{Kg, No, Lhold, Inh, Ltap, Lactivate, Pass, LhtK, LhtL, Klong, Khl, Khtl, Ldeactivate};
-use crate::layout::keys::{G, S};
+use crate::layout::types::{KeyCoords, LayerGroupId, Orientation};
+use crate::layout::switcher::{LayerOrder, LayerSwitcher};
+use crate::layout::types::KeymapEvent::{No, Lhold, Inh, Ltap, Pass, LhtK, LhtL, Klong, Khl, Khtl, Kos, Klock, Ku, Ldeactivate, Ltoggle, Llock, Lactivate, Lenable, Genable, Gdisable, Lmove, Mrel, Oflip};
+use crate::layout::keys::{action, unicode, KeyGroup, UnicodeMethod, G, S};
+use crate::layout::serialization::{load_layout, parse_layout};
+use crate::layout::xkb::XkbLayout;
+use crate::layout::krita::KritaShortcuts;
+use crate::xppen_hid::XpPenButtons;
+use crate::cheatsheet::{describe, render_markdown, render_svg, KeyLabels};
+use crate::simulator::Simulator;
+use crate::timer::{self, Clock, Deadline, ManualClock};
+use crate::pipeline::{Emitted, Pipeline};
+use crate::virtual_keyboard::frames::FrameQueue;
+use crate::virtual_keyboard::DeviceConfig;
+use crate::grab::{is_sibling, SiblingGrab};
+use crate::simulator::scenario::{run_scenario, run_scenarios};
 
 use self::testtime::TestTime;
 
@@ -17,10 +33,14 @@ impl TestDevice {
     pub(crate) const B02: KeyCoords = KeyCoords(0, 0, 1);
     pub(crate) const B03: KeyCoords = KeyCoords(0, 1, 0);
     pub(crate) const B04: KeyCoords = KeyCoords(0, 1, 1);
+    pub(crate) const B05: KeyCoords = KeyCoords(0, 2, 0);
 }
 
 const DEFAULT_LAYER_CONFIG: Layer = Layer{
     status_on_reset: crate::layout::types::LayerStatus::LayerActive,
+    groups: vec![],
+    exclusive_group: None,
+    priority: 0,
     inherit: None,
     on_active_keys: vec![],
     disable_active_on_press: false,
@@ -41,12 +61,15 @@ fn assert_emitted_keys(layout: &mut LayerSwitcher, keys: Vec<(Key, bool)>) {
     // The test could be done directly in the closure, but the asserts then
     // report a wrong caller line, because track_caller is still unstable
     // for closures.
-    layout.render(|k, v| {
-        received.push((k, v));
+    layout.render(|e| {
+        received.push(e);
     });
 
     let mut idx = 0;
-    for (k, v) in received {
+    for e in received {
+        let Emitted::Key(k, v) = e else {
+            panic!("Unexpected motion {:?}", e);
+        };
         assert!(idx < keys.len(), "Unexpected key {:?}/{}", k, v);
         assert_eq!(keys[idx].0, k, "Expected key {:?}/{} got {:?}/{}", keys[idx].0, keys[idx].1, k, v);
         assert_eq!(keys[idx].1, v, "Expected key {:?} state to be {} got {}", k, keys[idx].1, v);
@@ -681,6 +704,136 @@ fn test_hold_and_tap_layered_layout_long_press() {
     assert_emitted_keys(&mut layout, vec![]);
 }
 
+// Triple layout, toggled layer and locking of a held layer
+fn toggle_and_lock_layered_layout() -> Vec<Layer> {
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ Lhold(1),              Ltoggle(2),  ],
+            vec![ G().k(Key::KEY_B).p(), LhtL(1, 2), ],
+        ],
+    ];
+
+    let keymap_hold = vec![ // blocks
+        vec![ // rows
+            vec![ Pass,                  Llock,          ],
+            vec![ G().k(Key::KEY_E).p(), Ldeactivate(1), ],
+        ],
+    ];
+
+    let keymap_toggle = vec![ // blocks
+        vec![ // rows
+            vec![ Pass,                  Pass, ],
+            vec![ G().k(Key::KEY_T).p(), Pass, ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let hold_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+        on_active_keys: vec![Key::KEY_LEFTSHIFT],
+        keymap: keymap_hold,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let toggle_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+        on_active_keys: vec![Key::KEY_LEFTCTRL],
+        keymap: keymap_toggle,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![default_layer, hold_layer, toggle_layer];
+
+    layers
+}
+
+#[test]
+fn test_toggle_layer() {
+    let layout_vec = toggle_and_lock_layered_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 2]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(500));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_T, true), (Key::KEY_T, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 2]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(500));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+#[test]
+fn test_lock_hold_layer() {
+    let layout_vec = toggle_and_lock_layered_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(50));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // The layer stays active after the activating key is released
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B01), t.advance_ms(300));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_E, true), (Key::KEY_E, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+}
+
+#[test]
+fn test_lock_hold_and_tap_layer() {
+    let layout_vec = toggle_and_lock_layered_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B04), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(50));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // Short release does not trigger the tap layer of a locked layer
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B04), t.advance_ms(50));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+}
+
 // Dual layout, basic test simulating hold layer with key timeout behavior
 fn hold_and_tap_key_layered_layout() -> Vec<Layer> {
     let keymap_default = vec![ // blocks
@@ -1119,3 +1272,1991 @@ fn test_short_key_long_tap_layer_layout_long_press() {
     assert_emitted_keys(&mut layout, vec![]);
 }
 
+// Dual layout, one-shot and lockable modifiers used across a layer switch
+fn sticky_modifier_layout() -> Vec<Layer> {
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ Kos(G().k(Key::KEY_LEFTSHIFT)), G().k(Key::KEY_B).p(),          ],
+            vec![ Lhold(1),                       Klock(G().k(Key::KEY_LEFTCTRL)), ],
+        ],
+    ];
+
+    let keymap_shift = vec![ // blocks
+        vec![ // rows
+            vec![ Pass, G().k(Key::KEY_E).p(), ],
+            vec![ Pass, Pass,                  ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let shift_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+        keymap: keymap_shift,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![default_layer, shift_layer];
+
+    layers
+}
+
+#[test]
+fn test_one_shot_modifier() {
+    let layout_vec = sticky_modifier_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(500));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true), (Key::KEY_B, true), (Key::KEY_B, false), (Key::KEY_LEFTSHIFT, false)]);
+
+    // The modifier was applied to one key only
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+#[test]
+fn test_one_shot_modifier_held_key() {
+    let layout_vec = sticky_modifier_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true), (Key::KEY_B, true)]);
+
+    layout.process_keyevent(KeyStateChange::LongPress(TestDevice::B02), t.advance_ms(500));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, false), (Key::KEY_LEFTSHIFT, false)]);
+}
+
+#[test]
+fn test_one_shot_modifier_across_layers() {
+    let layout_vec = sticky_modifier_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true), (Key::KEY_E, true), (Key::KEY_E, false), (Key::KEY_LEFTSHIFT, false)]);
+
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+#[test]
+fn test_one_shot_modifier_cancel() {
+    let layout_vec = sticky_modifier_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t.advance_ms(100));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+#[test]
+fn test_lockable_modifier() {
+    let layout_vec = sticky_modifier_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // Double tap latches the modifier
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(100));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, true)]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(1000));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+
+    // The next tap unlatches it
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+#[test]
+fn test_lockable_modifier_single_tap() {
+    let layout_vec = sticky_modifier_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // Single tap behaves like a one-shot modifier
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(100));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, true), (Key::KEY_B, true), (Key::KEY_B, false), (Key::KEY_LEFTCTRL, false)]);
+
+    // Second tap was too slow to latch and cancels the one-shot instead
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.now());
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(400));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+// Triple layout, layers disabled on reset and enabled individually or as a group
+fn enable_layer_group_layout() -> Vec<Layer> {
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ Genable(0),    Gdisable(0),   ],
+            vec![ Lactivate(1),  Lenable(2),    ],
+        ],
+    ];
+
+    let keymap_advanced = vec![ // blocks
+        vec![ // rows
+            vec![ Pass, Pass, ],
+            vec![ Pass, Pass, ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let advanced_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerDisabled,
+        groups: vec![0],
+        on_active_keys: vec![Key::KEY_LEFTSHIFT],
+        keymap: keymap_advanced.clone(),
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let advanced_layer2 = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerDisabled,
+        groups: vec![0],
+        keymap: keymap_advanced,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![default_layer, advanced_layer, advanced_layer2];
+
+    layers
+}
+
+#[test]
+fn test_enable_layer_group() {
+    let layout_vec = enable_layer_group_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    // Disabled layer can't be activated
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // Enabled layers are not active until activated
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    // Disabling the group deactivates the active layer
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+}
+
+#[test]
+fn test_enable_layer() {
+    let layout_vec = enable_layer_group_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // Only the second layer was enabled
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.layer_enable(1);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.layer_disable(1);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTSHIFT, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+}
+
+const TOOL_MODES: LayerGroupId = 0;
+
+// Layout with three mutually exclusive tool mode layers and one independent layer
+fn exclusive_layer_group_layout() -> Vec<Layer> {
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ Lactivate(1), Lactivate(2), ],
+            vec![ Lhold(3),     Lmove(4),     ],
+            vec![ Lactivate(4),               ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let mode_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+        exclusive_group: Some(TOOL_MODES),
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![
+        default_layer,
+        Layer{ on_active_keys: vec![Key::KEY_1], ..mode_layer.clone() },
+        Layer{ on_active_keys: vec![Key::KEY_2], ..mode_layer.clone() },
+        Layer{ on_active_keys: vec![Key::KEY_3], ..mode_layer.clone() },
+        Layer{ on_active_keys: vec![Key::KEY_4], exclusive_group: None, ..mode_layer },
+    ];
+
+    layers
+}
+
+#[test]
+fn test_exclusive_layer_group() {
+    let layout_vec = exclusive_layer_group_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B05), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_4, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1, 4]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, false), (Key::KEY_2, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 2, 4]);
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_2, false), (Key::KEY_3, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 3, 4]);
+
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B03), t.advance_ms(300));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_3, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 4]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1, 4]);
+}
+
+#[test]
+fn test_layer_move() {
+    let layout_vec = exclusive_layer_group_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, false), (Key::KEY_4, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 4]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_2, true)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 2, 4]);
+
+    // Moving to an already active layer still deactivates the others
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_2, false)]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 4]);
+}
+
+// Triple layout, a held layer defined before an activated layer overrides it
+// only when it has a higher priority or was activated more recently
+fn layer_priority_layout(hold_priority: i32) -> Vec<Layer> {
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ Lhold(1),              Lactivate(2), ],
+            vec![ G().k(Key::KEY_B).p(), No,           ],
+        ],
+    ];
+
+    let keymap_hold = vec![ // blocks
+        vec![ // rows
+            vec![ Pass,                  Pass, ],
+            vec![ G().k(Key::KEY_1).p(), Pass, ],
+        ],
+    ];
+
+    let keymap_active = vec![ // blocks
+        vec![ // rows
+            vec![ Pass,                  Pass, ],
+            vec![ G().k(Key::KEY_2).p(), Pass, ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let hold_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+        priority: hold_priority,
+        keymap: keymap_hold,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let active_layer = Layer{
+        status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+        keymap: keymap_active,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![default_layer, hold_layer, active_layer];
+
+    layers
+}
+
+#[test]
+fn test_layer_definition_order() {
+    let layout_vec = layer_priority_layout(0);
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t);
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 1, 2]);
+
+    // The later defined layer wins
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_2, true), (Key::KEY_2, false)]);
+}
+
+#[test]
+fn test_layer_explicit_priority() {
+    let layout_vec = layer_priority_layout(1);
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t);
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, true), (Key::KEY_1, false)]);
+
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B01), t.advance_ms(10));
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_2, true), (Key::KEY_2, false)]);
+}
+
+#[test]
+fn test_layer_most_recently_activated() {
+    let layout_vec = layer_priority_layout(0);
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.set_layer_order(LayerOrder::MostRecentlyActivated);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t);
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    // The held layer was activated last and wins
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_1, true), (Key::KEY_1, false)]);
+
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B01), t.advance_ms(10));
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_2, true), (Key::KEY_2, false)]);
+}
+
+// Single layout, mouse buttons held like keys and pointer motion from clicks
+fn pointer_layout() -> Vec<Layer> {
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ G().k(Key::BTN_MIDDLE).p(),                     G().k(Key::BTN_RIGHT).p(),     ],
+            vec![ G().k(Key::KEY_LEFTCTRL).k(Key::BTN_LEFT).p(), Mrel(RelativeAxisType::REL_X, 10), ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![default_layer];
+
+    layers
+}
+
+#[test]
+fn test_pointer_buttons() {
+    let layout_vec = pointer_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    // Middle button drag
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![(Key::BTN_MIDDLE, true)]);
+
+    layout.process_keyevent(KeyStateChange::LongPress(TestDevice::B01), t.advance_ms(500));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B01), t.advance_ms(500));
+    assert_emitted_keys(&mut layout, vec![(Key::BTN_MIDDLE, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::BTN_RIGHT, true), (Key::BTN_RIGHT, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B03), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, true), (Key::BTN_LEFT, true), (Key::BTN_LEFT, false), (Key::KEY_LEFTCTRL, false)]);
+}
+
+#[test]
+fn test_pointer_motion() {
+    let layout_vec = pointer_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let t = TestTime::start();
+
+    assert_eq!(layout.get_used_axes(), vec![RelativeAxisType::REL_X]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t);
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B04), t);
+
+    let mut motion = Vec::new();
+    layout.render(|e| motion.push(e));
+    assert_eq!(motion, vec![
+        Emitted::Motion(RelativeAxisType::REL_X, 10),
+        Emitted::Motion(RelativeAxisType::REL_X, 10),
+    ]);
+}
+
+#[test]
+fn test_pointer_motion_order() {
+    // Ctrl+wheel zoom: the wheel moves while the layer holds Ctrl
+    let layout_vec = vec![
+        Layer {
+            keymap: vec![vec![vec![Lhold(1)]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            on_active_keys: vec![Key::KEY_LEFTCTRL],
+            keymap: vec![vec![vec![Pass, Mrel(RelativeAxisType::REL_WHEEL, 1)]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t);
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t);
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B01), t);
+
+    let mut emitted = Vec::new();
+    layout.render(|e| emitted.push(e));
+    assert_eq!(emitted, vec![
+        Emitted::Key(Key::KEY_LEFTCTRL, true),
+        Emitted::Motion(RelativeAxisType::REL_WHEEL, 1),
+        Emitted::Key(Key::KEY_LEFTCTRL, false),
+    ]);
+}
+
+#[test]
+fn test_named_actions() {
+    assert_eq!(action("volume_up"), Some(G().k(Key::KEY_VOLUMEUP)));
+    assert_eq!(action("brightness_down"), Some(G().k(Key::KEY_BRIGHTNESSDOWN)));
+    assert_eq!(action("volume"), None);
+}
+
+#[test]
+fn test_default_layout_media_layer() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let layout_vec = load_layout("test", &keyboard, &KritaShortcuts::default_scheme()).unwrap().layers;
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Click(XpPenButtons::XpB01), t);
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0, 6]);
+
+    // Rotary encoder positions as documented in the layout
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 11)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_VOLUMEUP, true), (Key::KEY_VOLUMEUP, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 10)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_VOLUMEDOWN, true), (Key::KEY_VOLUMEDOWN, false)]);
+
+    layout.process_keyevent(KeyStateChange::Click(XpPenButtons::XpB01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 11)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_SLASH, true), (Key::KEY_SLASH, false)]);
+}
+
+#[test]
+fn test_unicode_hex_entry_keygroups() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    assert_eq!(UnicodeMethod::hex_entry(&keyboard).unwrap().keygroups('\u{a9}'), Some(vec![
+        G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_U),
+        G().k(Key::KEY_A),
+        G().k(Key::KEY_9),
+        G().k(Key::KEY_SPACE),
+    ]));
+
+    // The digits are typed by symbol, AZERTY has A on KEY_Q and needs Shift for 9
+    let azerty = XkbLayout::new("fr", "", None).unwrap();
+    assert_eq!(UnicodeMethod::hex_entry(&azerty).unwrap().keygroups('\u{a9}'), Some(vec![
+        G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_U),
+        G().k(Key::KEY_Q),
+        G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_9),
+        G().k(Key::KEY_SPACE),
+    ]));
+}
+
+#[test]
+fn test_layout_unicode_method() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let krita = KritaShortcuts::default();
+
+    // The hex entry is the default
+    let layers = parse_layout("[[layer]]\nkeys = [{ unicode = \"é\" }]", &keyboard, &krita).unwrap().layers;
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 0)) == &unicode('é', &UnicodeMethod::hex_entry(&keyboard).unwrap()).unwrap());
+
+    let layers = parse_layout(r#"
+        unicode_method = { compose = "Menu", sequences = { "©" = ["o", "c"], "→" = ["-", ">"] } }
+
+        [[layer]]
+        keys = [{ unicode = "©" }, { unicode = "→" }]
+    "#, &keyboard, &krita).unwrap().layers;
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 0)) == &Ku('©', vec![
+        G().k(Key::KEY_COMPOSE), G().k(Key::KEY_O), G().k(Key::KEY_C),
+    ]));
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 1)) == &Ku('→', vec![
+        G().k(Key::KEY_COMPOSE), G().k(Key::KEY_MINUS), G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_DOT),
+    ]));
+
+    let err = parse_layout(r#"
+        unicode_method = { compose = "Menu", sequences = { "©" = ["o", "c"] } }
+
+        [[layer]]
+        keys = [{ unicode = "é" }]
+    "#, &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: key 0: Cannot type \"é\"");
+
+    let err = parse_layout("unicode_method = \"octal\"\n[[layer]]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "unicode_method: Unknown method \"octal\"");
+    let err = parse_layout("unicode_method = { compose = \"Ctrl+C\" }\n[[layer]]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "unicode_method: \"Ctrl+C\" is not a single key");
+}
+
+#[test]
+fn test_unicode_compose_keygroups() {
+    let compose = UnicodeMethod::Compose(Key::KEY_COMPOSE, vec![
+        ('→', vec![G().k(Key::KEY_MINUS), G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_DOT)]),
+    ]);
+
+    assert_eq!(compose.keygroups('→'), Some(vec![
+        G().k(Key::KEY_COMPOSE),
+        G().k(Key::KEY_MINUS),
+        G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_DOT),
+    ]));
+    assert_eq!(compose.keygroups('©'), None);
+}
+
+// Single layout, unicode characters typed using different methods
+fn unicode_layout() -> Vec<Layer> {
+    let hex_entry = UnicodeMethod::hex_entry(&XkbLayout::new("us", "", None).unwrap()).unwrap();
+    let compose = UnicodeMethod::Compose(Key::KEY_COMPOSE, vec![
+        ('©', vec![G().k(Key::KEY_O), G().k(Key::KEY_C)]),
+    ]);
+
+    let keymap_default = vec![ // blocks
+        vec![ // rows
+            vec![ unicode('→', &hex_entry).unwrap(), unicode('©', &compose).unwrap(), ],
+        ],
+    ];
+
+    let default_layer = Layer{
+        keymap: keymap_default,
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    let layers = vec![default_layer];
+
+    layers
+}
+
+#[test]
+fn test_unicode_output() {
+    let layout_vec = unicode_layout();
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let mut t = TestTime::start();
+
+    layout.process_keyevent(KeyStateChange::Pressed(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![
+        (Key::KEY_LEFTCTRL, true), (Key::KEY_LEFTSHIFT, true), (Key::KEY_U, true),
+        (Key::KEY_U, false), (Key::KEY_LEFTSHIFT, false), (Key::KEY_LEFTCTRL, false),
+        (Key::KEY_2, true), (Key::KEY_2, false),
+        (Key::KEY_1, true), (Key::KEY_1, false),
+        (Key::KEY_9, true), (Key::KEY_9, false),
+        (Key::KEY_2, true), (Key::KEY_2, false),
+        (Key::KEY_SPACE, true), (Key::KEY_SPACE, false),
+    ]);
+
+    // Nothing is left pressed
+    layout.process_keyevent(KeyStateChange::Released(TestDevice::B01), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![
+        (Key::KEY_COMPOSE, true), (Key::KEY_COMPOSE, false),
+        (Key::KEY_O, true), (Key::KEY_O, false),
+        (Key::KEY_C, true), (Key::KEY_C, false),
+    ]);
+}
+
+#[test]
+fn test_xkb_symbols_us() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+
+    assert_eq!(keyboard.keys("z"), Some(vec![Key::KEY_Z]));
+    assert_eq!(keyboard.keys("Z"), Some(vec![Key::KEY_Z]));
+    assert_eq!(keyboard.keys("Esc"), Some(vec![Key::KEY_ESC]));
+    assert_eq!(keyboard.keys("F12"), Some(vec![Key::KEY_F12]));
+    assert_eq!(keyboard.keys("!"), Some(vec![Key::KEY_LEFTSHIFT, Key::KEY_1]));
+    assert_eq!(keyboard.keys("no_such_key"), None);
+
+    assert_eq!(
+        keyboard.shortcut("Ctrl+Z"),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Z))
+    );
+    assert_eq!(
+        keyboard.shortcut("Ctrl++"),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_EQUAL))
+    );
+}
+
+#[test]
+fn test_xkb_symbols_qwertz_azerty() {
+    let qwertz = XkbLayout::new("de", "", None).unwrap();
+    assert_eq!(
+        qwertz.shortcut("Ctrl+Z"),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Y))
+    );
+    assert_eq!(qwertz.keys("["), Some(vec![Key::KEY_RIGHTALT, Key::KEY_8]));
+
+    let azerty = XkbLayout::new("fr", "", None).unwrap();
+    assert_eq!(
+        azerty.shortcut("Ctrl+Z"),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_W))
+    );
+    assert_eq!(azerty.keys("a"), Some(vec![Key::KEY_Q]));
+}
+
+#[test]
+fn test_default_layout_qwertz() {
+    let keyboard = XkbLayout::new("de", "", None).unwrap();
+    let layout_vec = load_layout("test", &keyboard, &KritaShortcuts::default_scheme()).unwrap().layers;
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let t = TestTime::start();
+
+    // Undo is on the Y scancode in QWERTZ
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 6)), t);
+    assert_emitted_keys(&mut layout, vec![
+        (Key::KEY_LEFTCTRL, true), (Key::KEY_Y, true),
+        (Key::KEY_Y, false), (Key::KEY_LEFTCTRL, false),
+    ]);
+}
+
+#[test]
+fn test_default_layout_unresolvable() {
+    // A Cyrillic layout has no key producing a latin B
+    let keyboard = XkbLayout::new("ru", "", None).unwrap();
+    let result = load_layout("test", &keyboard, &KritaShortcuts::default_scheme());
+    assert!(result.is_err_and(|e| e.starts_with("Cannot resolve shortcut")));
+}
+
+#[test]
+fn test_shortcut_parser() {
+    assert_eq!(
+        "Ctrl+Shift+A".parse::<KeyGroup>(),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_A))
+    );
+    assert_eq!(
+        "seq: Esc, Esc".parse::<KeyGroup>(),
+        Ok(S().k(Key::KEY_ESC).k(Key::KEY_ESC))
+    );
+    assert_eq!(
+        "mask(Shift): E".parse::<KeyGroup>(),
+        Ok(G().m(Key::KEY_LEFTSHIFT).k(Key::KEY_E))
+    );
+    assert_eq!(
+        "mask(Ctrl+Alt): seq: KEY_F1, BTN_LEFT".parse::<KeyGroup>(),
+        Ok(S().m(Key::KEY_LEFTCTRL).m(Key::KEY_LEFTALT).k(Key::KEY_F1).k(Key::BTN_LEFT))
+    );
+    assert_eq!("".parse::<KeyGroup>(), Ok(G()));
+
+    assert!("Ctrl+Nothing".parse::<KeyGroup>().is_err());
+    assert!("Ctrl++Z".parse::<KeyGroup>().is_err());
+    assert!("mask(Shift) E".parse::<KeyGroup>().is_err());
+}
+
+#[test]
+fn test_shortcut_display() {
+    let groups = vec![
+        G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_A),
+        S().k(Key::KEY_ESC).k(Key::KEY_ESC),
+        G().m(Key::KEY_LEFTSHIFT).k(Key::KEY_E),
+        S().m(Key::KEY_RIGHTALT).k(Key::KEY_LEFTBRACE).k(Key::BTN_LEFT),
+    ];
+
+    assert_eq!(groups[0].to_string(), "Ctrl+Shift+A");
+    assert_eq!(groups[1].to_string(), "seq: Esc, Esc");
+    assert_eq!(groups[2].to_string(), "mask(Shift): E");
+    assert_eq!(groups[3].to_string(), "mask(AltGr): seq: Leftbrace, BTN_LEFT");
+
+    for g in groups {
+        assert_eq!(g.to_string().parse::<KeyGroup>(), Ok(g));
+    }
+}
+
+#[test]
+fn test_xkb_shortcut_forms() {
+    let keyboard = XkbLayout::new("de", "", None).unwrap();
+
+    assert_eq!(
+        keyboard.shortcut("seq: Esc, z"),
+        Ok(S().k(Key::KEY_ESC).k(Key::KEY_Y))
+    );
+    assert_eq!(
+        keyboard.shortcut("mask(Shift): z"),
+        Ok(G().m(Key::KEY_LEFTSHIFT).k(Key::KEY_Y))
+    );
+
+    // A symbol needing a modifier cannot be a step in a sequence
+    assert!(keyboard.shortcut("seq: Esc, [").is_err());
+}
+
+#[test]
+fn test_krita_default_scheme() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let krita = KritaShortcuts::default_scheme();
+
+    assert_eq!(krita.action("edit_undo"), Some("Ctrl+Z"));
+    assert_eq!(krita.action("view_zoom_in"), Some("+"));
+    assert_eq!(
+        krita.shortcut("action:edit_undo", &keyboard),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Z))
+    );
+    assert_eq!(
+        krita.shortcut("action:view_zoom_in", &keyboard),
+        Ok(G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_EQUAL))
+    );
+    assert_eq!(
+        krita.shortcut("action:add_new_paint_layer", &keyboard),
+        Ok(G().k(Key::KEY_INSERT))
+    );
+
+    // Plain shortcuts still work
+    assert_eq!(krita.shortcut("Shift+E", &keyboard), Ok(G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_E)));
+    assert!(krita.shortcut("action:no_such_action", &keyboard).is_err());
+}
+
+#[test]
+fn test_krita_user_config_overrides() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let mut krita = KritaShortcuts::default_scheme();
+    krita.merge_str(include_str!("krita/custom.shortcuts")).unwrap();
+    krita.merge_str(include_str!("krita/kritashortcutsrc")).unwrap();
+
+    // Remapped in the scheme and then in the user configuration
+    assert_eq!(
+        krita.shortcut("action:KritaShape/KisToolBrush", &keyboard),
+        Ok(G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_B))
+    );
+    assert_eq!(
+        krita.shortcut("action:edit_undo", &keyboard),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Y))
+    );
+    assert_eq!(
+        krita.shortcut("action:increase_brush_size", &keyboard),
+        Ok(G().k(Key::KEY_LEFTCTRL).k(Key::KEY_BACKSLASH))
+    );
+
+    // Cleared shortcuts
+    assert_eq!(krita.action("erase_action"), None);
+    assert_eq!(krita.action("make_brush_color_darker"), None);
+    assert_eq!(krita.shortcut("action:erase_action", &keyboard), Ok(G()));
+
+    // Untouched actions keep the default
+    assert_eq!(krita.action("edit_redo"), Some("Ctrl+Shift+Z"));
+}
+
+#[test]
+fn test_krita_malformed_config() {
+    let mut krita = KritaShortcuts::default_scheme();
+    let err = krita
+        .merge_str("[Shortcuts]\nedit_undo=Ctrl+Y\nbroken line\n")
+        .unwrap_err();
+    assert!(err.contains("line 3"), "{}", err);
+
+    // Nothing from the broken file is applied
+    assert_eq!(krita, KritaShortcuts::default_scheme());
+
+    // Other sections are not checked
+    krita.merge_str("[General]\nbroken line\n[Shortcuts]\nedit_undo=Ctrl+Y\n").unwrap();
+    assert_eq!(krita.action("edit_undo"), Some("Ctrl+Y"));
+}
+
+#[test]
+fn test_default_layout_follows_krita_shortcuts() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let mut krita = KritaShortcuts::default_scheme();
+    krita.merge_str(include_str!("krita/kritashortcutsrc")).unwrap();
+
+    let layout_vec = load_layout("test", &keyboard, &krita).unwrap().layers;
+    let mut layout = LayerSwitcher::new(&layout_vec);
+    layout.start();
+    let t = TestTime::start();
+
+    // Undo was remapped to Ctrl+Y in Krita
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 6)), t);
+    assert_emitted_keys(&mut layout, vec![
+        (Key::KEY_LEFTCTRL, true), (Key::KEY_Y, true),
+        (Key::KEY_Y, false), (Key::KEY_LEFTCTRL, false),
+    ]);
+}
+
+#[test]
+fn test_cheatsheet_labels() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    assert_eq!(describe(&No, &keyboard), KeyLabels::default());
+    assert_eq!(
+        describe(&Klong(G().k(Key::KEY_F12), G().k(Key::KEY_LEFTCTRL).k(Key::KEY_A)), &keyboard),
+        KeyLabels {
+            tap: Some("F12".to_string()),
+            hold: None,
+            long: Some("Ctrl+A".to_string()),
+        }
+    );
+    assert_eq!(
+        describe(&LhtK(2, G().k(Key::KEY_LEFTSHIFT).k(Key::KEY_E)), &keyboard),
+        KeyLabels {
+            tap: Some("Shift+E".to_string()),
+            hold: Some("layer 2".to_string()),
+            long: None,
+        }
+    );
+    assert_eq!(
+        describe(&LhtK(5, G()), &keyboard),
+        KeyLabels {
+            tap: None,
+            hold: Some("layer 5".to_string()),
+            long: None,
+        }
+    );
+}
+
+#[test]
+fn test_cheatsheet_markdown() {
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![
+                G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Z).p(),
+                No,
+                Lhold(1),
+            ]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            on_active_keys: vec![Key::KEY_LEFTCTRL],
+            keymap: vec![vec![vec![
+                No, No, No, No, No, No, No, No, No, No,
+                G().k(Key::KEY_MINUS).p(),
+                G().k(Key::KEY_EQUAL).p(),
+            ]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let md = render_markdown(&layers, &keyboard);
+    assert!(md.contains("## Layer 0\n\n| Key | Tap | Hold | Long |"));
+    assert!(md.contains("| 0 | `Ctrl+Z` |  |  |\n| 2 |  | `layer 1` |  |\n"));
+    assert!(!md.contains("| 1 |"));
+    assert!(md.contains("## Layer 1\n\nHolds `Ctrl` while active."));
+    assert!(md.contains("| CCW (10) | `-` |  |  |\n| CW (11) | `=` |  |  |\n"));
+}
+
+#[test]
+fn test_cheatsheet_keyboard_layout() {
+    // Ctrl+Z resolves to the KEY_Y scancode on QWERTZ and is labelled by the symbol
+    let keyboard = XkbLayout::new("de", "", None).unwrap();
+    let undo = keyboard.shortcut("Ctrl+Z").unwrap();
+    assert_eq!(undo, G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Y));
+    assert_eq!(describe(&undo.p(), &keyboard).tap, Some("Ctrl+Z".to_string()));
+    assert_eq!(
+        describe(&keyboard.shortcut("seq: Esc, F12").unwrap().p(), &keyboard).tap,
+        Some("seq: Escape, F12".to_string())
+    );
+}
+
+#[test]
+fn test_cheatsheet_inherited_keys() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![G().k(Key::KEY_A).p(), G().k(Key::KEY_B).p(), G().k(Key::KEY_C).p()]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            keymap: vec![vec![vec![G().k(Key::KEY_D).p(), Inh, No]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            inherit: Some(1),
+            keymap: vec![vec![vec![Inh, Pass, Inh]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+
+    let md = render_markdown(&layers, &keyboard);
+    // Layer 1 has no parent, the inherited key 1 falls through to the base layer
+    assert!(md.contains("## Layer 1\n\n| Key | Tap | Hold | Long |\n|-----|-----|------|------|\n| 0 | `D` |  |  |\n| 1 | `B` |  |  |\n\n"));
+    // Layer 2 inherits from layer 1, key 2 is disabled there
+    assert!(md.ends_with("## Layer 2\n\n| Key | Tap | Hold | Long |\n|-----|-----|------|------|\n| 0 | `D` |  |  |\n| 1 | `B` |  |  |\n"));
+}
+
+#[test]
+fn test_cheatsheet_svg_orientation() {
+    let layer = Layer {
+        keymap: vec![vec![vec![G().k(Key::KEY_B).p(), LhtK(2, G().k(Key::KEY_A))]]],
+        ..DEFAULT_LAYER_CONFIG
+    };
+
+    // Key 0 is top left next to the rotary, the rotated pad has it bottom right
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let layers = [layer];
+    let svg = render_svg(&layers, 0, Orientation::Normal, &keyboard);
+    assert!(svg.contains(concat!(
+        r##"<rect x="35.5" y="2.5" width="18" height="18" rx="1.8" fill="#fff" stroke="#333" stroke-width="0.3"/>"##, "\n",
+        r#"  <text x="36.5" y="5.5" font-size="2.2" class="index">0</text>"#, "\n",
+        r#"  <text x="44.5" y="12.5" font-size="3" class="tap">B</text>"#,
+    )));
+    assert!(svg.contains(r#"<rect x="56.5" y="44.5" width="39" height="18""#));
+    assert!(svg.contains(">hold: layer 2</text>"));
+
+    // The rotated pad has the physical key 9 top left acting as the logical key 0
+    let svg = render_svg(&layers, 0, Orientation::Rotated, &keyboard);
+    assert!(svg.contains(concat!(
+        r##"<rect x="3.5" y="2.5" width="18" height="18" rx="1.8" fill="#fff" stroke="#333" stroke-width="0.3"/>"##, "\n",
+        r#"  <text x="4.5" y="5.5" font-size="2.2" class="index">0</text>"#, "\n",
+        r#"  <text x="12.5" y="12.5" font-size="3" class="tap">B</text>"#,
+    )));
+    assert!(svg.contains(r#"<rect x="24.5" y="2.5" width="39" height="18""#));
+    assert!(svg.contains(r#"<circle cx="108.0" cy="53.0""#));
+}
+
+#[test]
+fn test_orientation_mapping() {
+    assert_eq!(Orientation::Normal.logical(KeyCoords(0, 0, 3)), KeyCoords(0, 0, 3));
+    assert_eq!(Orientation::Rotated.logical(KeyCoords(0, 0, 0)), KeyCoords(0, 0, 9));
+    assert_eq!(Orientation::Rotated.logical(KeyCoords(0, 0, 9)), KeyCoords(0, 0, 0));
+    assert_eq!(Orientation::Rotated.logical(KeyCoords(0, 0, 10)), KeyCoords(0, 0, 11));
+    assert_eq!(Orientation::Rotated.logical(KeyCoords(0, 0, 11)), KeyCoords(0, 0, 10));
+    assert_eq!(Orientation::Rotated.flipped(), Orientation::Normal);
+}
+
+#[test]
+fn test_rotated_orientation() {
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![
+                /* 0 */ G().k(Key::KEY_A).p(),
+                /* 1 */ Oflip,
+                No, No, No, No, No, No,
+                /* 8 */ Lhold(1),
+                /* 9 */ G().k(Key::KEY_B).p(),
+                /* CCW */ G().k(Key::KEY_MINUS).p(),
+                /* CW */ G().k(Key::KEY_EQUAL).p(),
+            ]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            keymap: vec![],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+
+    let mut layout = LayerSwitcher::new(&layers);
+    layout.set_orientation(Orientation::Rotated);
+    layout.start();
+    let mut t = TestTime::start();
+
+    // Physical key 9 acts as the logical key 0
+    layout.process_keyevent(KeyStateChange::Pressed(KeyCoords(0, 0, 9)), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_A, true)]);
+    layout.process_keyevent(KeyStateChange::Released(KeyCoords(0, 0, 9)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_A, false)]);
+
+    // Rotary directions are swapped
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 11)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_MINUS, true), (Key::KEY_MINUS, false)]);
+
+    // Hold the logical key 8 (physical 1) and flip the orientation
+    // using the logical key 1 (physical 8)
+    layout.process_keyevent(KeyStateChange::Pressed(KeyCoords(0, 0, 1)), t.advance_ms(10));
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+    layout.process_keyevent(KeyStateChange::Pressed(KeyCoords(0, 0, 8)), t.advance_ms(10));
+    layout.process_keyevent(KeyStateChange::Released(KeyCoords(0, 0, 8)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![]);
+    assert_eq!(layout.get_orientation(), Orientation::Normal);
+
+    // The held key keeps its rotated position until released
+    layout.process_keyevent(KeyStateChange::Released(KeyCoords(0, 0, 1)), t.advance_ms(10));
+    assert_eq!(layout.get_active_layers(), vec![0]);
+
+    // Normal orientation now
+    layout.process_keyevent(KeyStateChange::Click(KeyCoords(0, 0, 9)), t.advance_ms(10));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_B, true), (Key::KEY_B, false)]);
+}
+
+#[test]
+fn test_layout_file() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let krita = KritaShortcuts::default_scheme();
+
+    let layers = parse_layout(include_str!("../../layouts/example.toml"), &keyboard, &krita).unwrap().layers;
+    assert_eq!(layers.len(), 3);
+
+    let layers = parse_layout(r#"
+        [[layer]]
+        status = "active"
+        keys = [
+            "Ctrl+Z",
+            { tap = "B", hold = 1 },
+            { tap = "F12", long = "action:deselect" },
+            { motion = "REL_WHEEL", value = -1 },
+            "media:mute",
+        ]
+
+        [[layer]]
+        on_active_keys = "Ctrl"
+        default = "no"
+        keys = ["pass", { toggle = 0 }]
+    "#, &keyboard, &krita).unwrap().layers;
+
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 0)) == &G().k(Key::KEY_LEFTCTRL).k(Key::KEY_Z).p());
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 1)) == &LhtK(1, G().k(Key::KEY_B)));
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 2)) == &Klong(
+        G().k(Key::KEY_F12),
+        G().k(Key::KEY_LEFTCTRL).k(Key::KEY_LEFTSHIFT).k(Key::KEY_A),
+    ));
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 3)) == &Mrel(RelativeAxisType::REL_WHEEL, -1));
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 4)) == &G().k(Key::KEY_MUTE).p());
+    assert!(layers[0].get_key_event(KeyCoords(0, 0, 5)) == &Pass);
+    assert!(layers[1].get_key_event(KeyCoords(0, 0, 1)) == &Ltoggle(0));
+    assert!(layers[1].get_key_event(KeyCoords(0, 0, 5)) == &No);
+    assert_eq!(layers[1].on_active_keys, vec![Key::KEY_LEFTCTRL]);
+
+    // Errors point to the problem
+    let err = parse_layout("[[layer]]\nkeys = [\"no\", { hold = 3 }]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: refers to a missing layer 3");
+    let err = parse_layout("[[layer]]\nkeys = [\"Ctrl+Nothing\"]", &keyboard, &krita).err().unwrap();
+    assert!(err.starts_with("layer 0: key 0: Unknown key \"Nothing\""), "{}", err);
+    assert!(parse_layout("", &keyboard, &krita).is_err());
+
+    // Misspelled and conflicting keys of an action table are not ignored
+    let err = parse_layout("[[layer]]\nkeys = [{ hold_layr = 1 }]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: key 0: Unknown action key \"hold_layr\"");
+    let err = parse_layout("[[layer]]\nkeys = [{ key = \"A\", long = \"B\" }]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: key 0: \"long\" cannot be combined with \"key\"");
+    let err = parse_layout("[[layer]]\nkeys = [{ hold = 1, tap = \"A\", tap_layer = 1 }, \"no\"]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: key 0: \"tap\" cannot be combined with \"tap_layer\"");
+
+    // Layers of equal priority are ordered by position unless asked otherwise
+    let layout = parse_layout("[[layer]]", &keyboard, &krita).unwrap();
+    assert_eq!(layout.layer_order, LayerOrder::Priority);
+    let layout = parse_layout("layer_order = \"recent\"\n[[layer]]", &keyboard, &krita).unwrap();
+    assert_eq!(layout.layer_order, LayerOrder::MostRecentlyActivated);
+    let err = parse_layout("layer_order = \"newest\"\n[[layer]]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "Unknown layer order \"newest\"");
+
+    // Misspelled layer and layout keys are not ignored either
+    let err = parse_layout("[[layer]]\nprority = 2", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: Unknown layer key \"prority\"");
+    let err = parse_layout("unicode_methd = \"hex\"\n[[layer]]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "Unknown layout key \"unicode_methd\"");
+
+    // Integers out of the i32 range are not truncated
+    let err = parse_layout("[[layer]]\npriority = 4294967297", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: \"priority\" 4294967297 is out of range");
+    let err = parse_layout("[[layer]]\nkeys = [{ motion = \"REL_X\", value = -4294967296 }]", &keyboard, &krita).err().unwrap();
+    assert_eq!(err, "layer 0: key 0: Motion value -4294967296 is out of range");
+}
+
+#[test]
+fn test_simulator_script() {
+    let keyboard = XkbLayout::new("us", "", None).unwrap();
+    let layout = parse_layout(r#"
+        [[layer]]
+        status = "active"
+        keys = [{ tap = "B", hold = 1 }, "Ctrl+Z", "no", "no", "no", "no", "no", "no", "no", "no", "-", "="]
+
+        [[layer]]
+        keys = ["pass", "K"]
+    "#, &keyboard, &KritaShortcuts::default()).unwrap();
+
+    let mut simulator = Simulator::new(&layout, Orientation::Normal);
+    let mut out = Vec::new();
+    let script = "press 0\nwait 300\ntap 1\nrelease 0\n# tap\ntap 0\ncw\nccw\nbogus 1\n";
+    simulator.run(script.as_bytes(), &mut out).unwrap();
+
+    assert_eq!(String::from_utf8(out).unwrap(), concat!(
+        "  layers [0]\n",
+        "[    0 ms] press 0\n",
+        "  layers [0, 1]\n",
+        "[    0 ms] wait 300\n",
+        "[  300 ms] tap 1\n",
+        "  > KEY_K pressed\n",
+        "  > KEY_K released\n",
+        "[  300 ms] release 0\n",
+        "  layers [0]\n",
+        "[  300 ms] tap 0\n",
+        "  > KEY_B pressed\n",
+        "  > KEY_B released\n",
+        "[  300 ms] cw\n",
+        "  > KEY_EQUAL pressed\n",
+        "  > KEY_EQUAL released\n",
+        "[  300 ms] ccw\n",
+        "  > KEY_MINUS pressed\n",
+        "  > KEY_MINUS released\n",
+        "[  300 ms] bogus 1\n",
+        "  error on line 9: Unknown command \"bogus\"\n",
+    ));
+    assert_eq!(simulator.elapsed().as_millis(), 300);
+}
+
+#[test]
+fn test_scenarios() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tests/scenarios");
+    let results = run_scenarios(&dir).unwrap();
+    assert!(results.len() >= 5);
+
+    let failures: Vec<String> = results
+        .into_iter()
+        .filter_map(|(path, r)| r.err().map(|e| format!("{}: {}", path.display(), e)))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_scenario_mismatch() {
+    let dir = std::env::temp_dir().join(format!("xppen-scenario-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("wrong.toml");
+    std::fs::write(&path, r#"
+        [[layer]]
+        status = "active"
+        keys = ["A"]
+
+        [[step]]
+        input = "tap 0"
+        keys = [["KEY_B", true], ["KEY_B", false]]
+    "#).unwrap();
+
+    let err = run_scenario(&path).err().unwrap();
+    assert_eq!(
+        err,
+        "step 0: emitted keys [Key(KEY_A, true), Key(KEY_A, false)], expected [Key(KEY_B, true), Key(KEY_B, false)]"
+    );
+
+    // A misspelled scenario key is not taken for a layout key
+    std::fs::write(&path, "layout = \"builtin\"\norientaton = \"rotated\"\n[[step]]").unwrap();
+    let err = run_scenario(&path).err().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(err, "Unknown scenario key \"orientaton\"");
+}
+
+fn detected_events(detector: &mut ChangeDetector<XpPenButtons>) -> Vec<KeyStateChange<XpPenButtons>> {
+    std::iter::from_fn(|| detector.next()).collect()
+}
+
+#[test]
+fn test_change_detector_order() {
+    use XpPenButtons::*;
+
+    let mut t = TestTime::start();
+    let mut detector = ChangeDetector::new();
+
+    detector.analyze(XpB05 | XpB02 | XpB09, t.now());
+    assert_eq!(detected_events(&mut detector), vec![
+        KeyStateChange::Pressed(XpB02),
+        KeyStateChange::Pressed(XpB05),
+        KeyStateChange::Pressed(XpB09),
+    ]);
+
+    // Releases come first, then presses and clicks by the button index
+    detector.analyze(XpB01 | XpB05 | XpRoCW, t.advance_ms(10));
+    assert_eq!(detected_events(&mut detector), vec![
+        KeyStateChange::Released(XpB02),
+        KeyStateChange::Released(XpB09),
+        KeyStateChange::Pressed(XpB01),
+        KeyStateChange::Click(XpRoCW),
+    ]);
+
+    // Unconsumed events of consecutive reports are kept in order
+    detector.analyze(XpB01 | XpB03, t.advance_ms(10));
+    detector.analyze(EnumSet::from(XpB03), t.advance_ms(10));
+    detector.tick(t.advance_ms(300));
+    assert_eq!(detected_events(&mut detector), vec![
+        KeyStateChange::Released(XpB05),
+        KeyStateChange::Pressed(XpB03),
+        KeyStateChange::Released(XpB01),
+        KeyStateChange::LongPress(XpB03),
+    ]);
+    assert!(detector.next().is_none());
+}
+
+// A hold/tap key released in the same report as the next key is pressed
+// must be resolved as a tap before the next key is processed
+#[test]
+fn test_change_detector_release_before_press() {
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![LhtK(1, G().k(Key::KEY_A)), G().k(Key::KEY_B).p()]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            keymap: vec![vec![vec![Pass, G().k(Key::KEY_C).p()]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+    let mut layout = LayerSwitcher::new(&layers);
+    layout.start();
+
+    let mut t = TestTime::start();
+    let mut detector = ChangeDetector::new();
+
+    detector.analyze(EnumSet::from(XpPenButtons::XpB01), t.now());
+    detector.analyze(EnumSet::from(XpPenButtons::XpB02), t.advance_ms(50));
+    while let Some(ev) = detector.next() {
+        layout.process_keyevent(ev, t.now());
+    }
+
+    assert_emitted_keys(&mut layout, vec![
+        (Key::KEY_A, true), (Key::KEY_A, false),
+        (Key::KEY_B, true),
+    ]);
+}
+
+#[test]
+fn test_debounce_eager() {
+    use XpPenButtons::*;
+
+    let mut t = TestTime::start();
+    let mut debouncer = Debouncer::new(DebounceMode::Eager, Duration::from_millis(10));
+    let mut detector = ChangeDetector::new();
+    let none = EnumSet::<XpPenButtons>::empty();
+
+    // Press, chatter and release
+    for (ms, input) in [(0, EnumSet::from(XpB07)), (2, none), (3, EnumSet::from(XpB07)), (40, none)] {
+        let buttons = debouncer.update(input, t.advance_ms(ms));
+        detector.analyze(buttons, t.now());
+    }
+    assert_eq!(detected_events(&mut detector), vec![
+        KeyStateChange::Pressed(XpB07),
+        KeyStateChange::Released(XpB07),
+    ]);
+    assert_eq!(debouncer.bounces(XpB07), 1);
+
+    // A release bouncing right after the press is applied once the window elapses
+    assert_eq!(debouncer.update(EnumSet::from(XpB02), t.advance_ms(100)), XpB02);
+    assert_eq!(debouncer.update(none, t.advance_ms(4)), XpB02);
+    assert!(debouncer.is_pending());
+    assert_eq!(debouncer.poll(t.advance_ms(3)), None);
+    assert_eq!(debouncer.poll(t.advance_ms(3)), Some(none));
+    assert!(!debouncer.is_pending());
+    assert_eq!(debouncer.total_bounces(), 1);
+}
+
+#[test]
+fn test_debounce_deferred() {
+    use XpPenButtons::*;
+
+    let mut t = TestTime::start();
+    let mut debouncer = Debouncer::new(DebounceMode::Deferred, Duration::from_millis(10));
+    debouncer.set_window(XpB06, Duration::from_millis(30));
+    let none = EnumSet::<XpPenButtons>::empty();
+
+    // A spike shorter than the window is never reported
+    assert_eq!(debouncer.update(EnumSet::from(XpB01), t.now()), none);
+    assert_eq!(debouncer.update(none, t.advance_ms(5)), none);
+    assert_eq!(debouncer.poll(t.advance_ms(20)), None);
+    assert_eq!(debouncer.bounces(XpB01), 1);
+
+    // Changes are reported after the per button window
+    assert_eq!(debouncer.update(XpB01 | XpB06, t.advance_ms(100)), none);
+    assert_eq!(debouncer.poll(t.advance_ms(10)), Some(EnumSet::from(XpB01)));
+    assert_eq!(debouncer.poll(t.advance_ms(10)), None);
+    assert_eq!(debouncer.poll(t.advance_ms(10)), Some(XpB01 | XpB06));
+
+    // Bouncing restarts the window
+    assert_eq!(debouncer.update(EnumSet::from(XpB06), t.advance_ms(100)), XpB01 | XpB06);
+    assert_eq!(debouncer.update(XpB01 | XpB06, t.advance_ms(8)), XpB01 | XpB06);
+    assert_eq!(debouncer.update(EnumSet::from(XpB06), t.advance_ms(1)), XpB01 | XpB06);
+    assert_eq!(debouncer.poll(t.advance_ms(9)), None);
+    assert_eq!(debouncer.poll(t.advance_ms(1)), Some(EnumSet::from(XpB06)));
+    assert_eq!(debouncer.bounces(XpB01), 2);
+    assert_eq!(debouncer.bounces(XpB06), 0);
+
+    // The rotary is never delayed
+    assert_eq!(debouncer.update(XpB06 | XpRoCW, t.advance_ms(100)), XpB06 | XpRoCW);
+    assert_eq!(debouncer.update(EnumSet::from(XpB06), t.advance_ms(1)), XpB06);
+    assert_eq!(debouncer.update(XpB06 | XpRoCW, t.advance_ms(1)), XpB06 | XpRoCW);
+    assert_eq!(debouncer.total_bounces(), 2);
+}
+
+#[test]
+fn test_debounce_reset() {
+    use XpPenButtons::*;
+
+    let mut t = TestTime::start();
+    let mut debouncer = Debouncer::new(DebounceMode::Deferred, Duration::from_millis(10));
+    debouncer.set_window(XpB02, Duration::from_millis(30));
+    debouncer.update(EnumSet::from(XpB01), t.now());
+    debouncer.update(EnumSet::empty(), t.advance_ms(1));
+    debouncer.update(EnumSet::from(XpB02), t.advance_ms(1));
+    assert!(debouncer.is_pending());
+
+    // Pending changes are dropped, the configuration and statistics stay
+    debouncer.reset();
+    assert!(!debouncer.is_pending());
+    assert_eq!(debouncer.state(), EnumSet::empty());
+    assert_eq!(debouncer.next_deadline(), None);
+    assert_eq!(debouncer.get_window(XpB02), Duration::from_millis(30));
+    assert_eq!(debouncer.total_bounces(), 1);
+}
+
+#[test]
+fn test_deadlines() {
+    use XpPenButtons::*;
+
+    let mut t = TestTime::start();
+    let t0 = t.now();
+    let mut detector = ChangeDetector::new();
+    let mut debouncer = Debouncer::new(DebounceMode::Deferred, Duration::from_millis(10));
+    assert_eq!(detector.next_deadline(), None);
+    assert_eq!(debouncer.next_deadline(), None);
+    assert_eq!(timer::timeout(timer::earliest([detector.next_deadline(), debouncer.next_deadline()]), t.now()), None);
+
+    // The rotary has no long press
+    detector.analyze(XpB02 | XpRoCW, t.now());
+    detector.analyze(XpB02 | XpB05, t.advance_ms(50));
+    // A long press needs more than the threshold
+    let after = |ms| t0 + Duration::from_millis(ms) + Duration::from_nanos(1);
+    assert_eq!(detector.next_deadline(), Some(after(200)));
+    debouncer.update(EnumSet::from(XpB03), t.now());
+    assert_eq!(debouncer.next_deadline(), Some(t0 + Duration::from_millis(60)));
+
+    let deadline = timer::earliest([detector.next_deadline(), debouncer.next_deadline()]);
+    assert_eq!(deadline, Some(t0 + Duration::from_millis(60)));
+    assert_eq!(timer::timeout(deadline, t.now()), Some(Duration::from_millis(10)));
+    assert_eq!(timer::timeout(deadline, t.now() + Duration::from_millis(20)), Some(Duration::ZERO));
+
+    // The long press is detected exactly at the deadline
+    detected_events(&mut detector);
+    detector.tick(t0 + Duration::from_millis(200));
+    assert!(detector.next().is_none());
+    detector.tick(after(200));
+    assert_eq!(detected_events(&mut detector), vec![KeyStateChange::LongPress(XpB02)]);
+    assert_eq!(detector.next_deadline(), Some(after(250)));
+}
+
+#[test]
+fn test_pipeline_virtual_time() {
+    use XpPenButtons::*;
+
+    let layers = vec![Layer {
+        keymap: vec![vec![vec![Klong(G().k(Key::KEY_A), G().k(Key::KEY_B)), G().k(Key::KEY_C).p()]]],
+        ..DEFAULT_LAYER_CONFIG
+    }];
+    let mut switcher = LayerSwitcher::new(&layers);
+    switcher.start();
+
+    let clock = ManualClock::new();
+    let t0 = clock.now();
+    let debouncer = Debouncer::new(DebounceMode::Deferred, Duration::from_millis(10));
+    let mut pipeline = Pipeline::new(&clock, debouncer, switcher);
+    pipeline.set_long_press(Duration::from_millis(500));
+
+    let mut emitted = Vec::new();
+    let mut run = |pipeline: &mut Pipeline<&ManualClock>| pipeline.process_all(|e| emitted.push(e));
+
+    // The press is reported after the debounce window, at the time of the tick
+    pipeline.report(EnumSet::from(XpB01));
+    assert_eq!(pipeline.timeout(), Some(Duration::from_millis(10)));
+    clock.advance(Duration::from_millis(10));
+    pipeline.tick();
+    assert_eq!(pipeline.now(), t0 + Duration::from_millis(10));
+    run(&mut pipeline);
+
+    // The long press threshold applies to both the detector and the switcher
+    let timeout = Duration::from_millis(500) + Duration::from_nanos(1);
+    assert_eq!(pipeline.timeout(), Some(timeout));
+    clock.advance(Duration::from_millis(500));
+    pipeline.tick();
+    run(&mut pipeline);
+    clock.advance(timeout - Duration::from_millis(500));
+    pipeline.tick();
+    run(&mut pipeline);
+    assert_eq!(pipeline.timeout(), None);
+
+    pipeline.report(EnumSet::empty());
+    clock.advance(Duration::from_millis(10));
+    pipeline.tick();
+    run(&mut pipeline);
+
+    assert_eq!(emitted, vec![Emitted::Key(Key::KEY_B, true), Emitted::Key(Key::KEY_B, false)]);
+}
+
+// Send all queued frames, waiting for each deadline
+fn frames(queue: &mut FrameQueue, clock: &ManualClock) -> Vec<Vec<Emitted>> {
+    let mut out = Vec::new();
+    while !queue.is_empty() {
+        if let Some(deadline) = queue.next_deadline() {
+            clock.set(deadline);
+        }
+        out.push(queue.next_frame(clock.now()).unwrap());
+    }
+    out
+}
+
+#[test]
+fn test_output_frames() {
+    let key = |k, s| Emitted::Key(k, s);
+    let clock = ManualClock::new();
+    let t0 = clock.now();
+    let mut queue = FrameQueue::new(Duration::from_millis(2));
+
+    for k in [Key::KEY_LEFTCTRL, Key::KEY_LEFTSHIFT, Key::KEY_A] {
+        queue.push(key(k, true));
+    }
+    for k in [Key::KEY_A, Key::KEY_LEFTSHIFT, Key::KEY_LEFTCTRL] {
+        queue.push(key(k, false));
+    }
+    queue.push(Emitted::Motion(RelativeAxisType::REL_WHEEL, 1));
+    queue.push(Emitted::Motion(RelativeAxisType::REL_HWHEEL, -1));
+    queue.push(Emitted::Motion(RelativeAxisType::REL_WHEEL, 1));
+    queue.push(key(Key::KEY_ESC, true));
+    queue.push(key(Key::KEY_ESC, false));
+    queue.push(key(Key::KEY_ESC, true));
+    queue.push(key(Key::KEY_ESC, false));
+
+    // The first frame goes out immediately
+    assert!(queue.next_frame(clock.now()).is_some());
+    assert_eq!(queue.next_frame(clock.now()), None);
+    assert_eq!(queue.next_deadline(), Some(t0 + Duration::from_millis(2)));
+
+    assert_eq!(frames(&mut queue, &clock), vec![
+        vec![key(Key::KEY_A, true)],
+        vec![key(Key::KEY_A, false), key(Key::KEY_LEFTSHIFT, false), key(Key::KEY_LEFTCTRL, false)],
+        vec![Emitted::Motion(RelativeAxisType::REL_WHEEL, 1), Emitted::Motion(RelativeAxisType::REL_HWHEEL, -1)],
+        vec![Emitted::Motion(RelativeAxisType::REL_WHEEL, 1)],
+        vec![key(Key::KEY_ESC, true)],
+        vec![key(Key::KEY_ESC, false)],
+        vec![key(Key::KEY_ESC, true)],
+        vec![key(Key::KEY_ESC, false)],
+    ]);
+    assert_eq!(clock.now(), t0 + Duration::from_millis(16));
+    assert_eq!(queue.next_deadline(), None);
+}
+
+#[test]
+fn test_output_key_state() {
+    let key = |k, s| Emitted::Key(k, s);
+    let clock = ManualClock::new();
+    let mut queue = FrameQueue::new(Duration::ZERO);
+
+    // An orphan release is dropped
+    queue.push(key(Key::KEY_B, false));
+    assert!(queue.is_empty());
+
+    // Ctrl held by a layer and pressed again by a shortcut
+    queue.push(key(Key::KEY_LEFTCTRL, true));
+    queue.push(key(Key::KEY_LEFTCTRL, true));
+    queue.push(key(Key::KEY_Z, true));
+    queue.push(key(Key::KEY_Z, false));
+    queue.push(key(Key::KEY_LEFTCTRL, false));
+    assert!(queue.is_down(Key::KEY_LEFTCTRL));
+    assert!(!queue.is_down(Key::KEY_Z));
+    queue.push(key(Key::KEY_LEFTCTRL, false));
+    queue.push(key(Key::KEY_LEFTCTRL, false));
+    assert_eq!(queue.keys_down().count(), 0);
+
+    assert_eq!(frames(&mut queue, &clock), vec![
+        vec![key(Key::KEY_LEFTCTRL, true)],
+        vec![key(Key::KEY_Z, true)],
+        vec![key(Key::KEY_Z, false), key(Key::KEY_LEFTCTRL, false)],
+    ]);
+}
+
+#[test]
+fn test_layer_timeout() {
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![Lactivate(1), G().k(Key::KEY_A).p()]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            on_active_keys: vec![Key::KEY_LEFTCTRL],
+            timeout: Some(Duration::from_millis(1000)),
+            on_timeout_layer: Some(2),
+            keymap: vec![vec![vec![Pass, G().k(Key::KEY_B).p()]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            keymap: vec![vec![vec![Pass, G().k(Key::KEY_C).p()]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+    let mut layout = LayerSwitcher::new(&layers);
+    layout.start();
+    let mut t = TestTime::start();
+    assert_eq!(layout.next_deadline(), None);
+
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, true)]);
+    assert_eq!(layout.next_deadline(), Some(t.now() + Duration::from_millis(1000)));
+
+    layout.tick(t.advance_ms(999));
+    assert_eq!(layout.get_active_layers(), vec![0, 1]);
+
+    layout.tick(t.advance_ms(1));
+    assert_eq!(layout.get_active_layers(), vec![0, 2]);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, false)]);
+    assert_eq!(layout.next_deadline(), None);
+
+    // A key event after the timeout is handled without the timed out layer,
+    // even when the tick was missed
+    layout.start();
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B01), t);
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, true)]);
+    layout.process_keyevent(KeyStateChange::Click(TestDevice::B02), t.advance_ms(2000));
+    assert_emitted_keys(&mut layout, vec![(Key::KEY_LEFTCTRL, false), (Key::KEY_C, true), (Key::KEY_C, false)]);
+}
+
+#[test]
+fn test_pipeline_layer_timeout() {
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![Lactivate(1)]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            on_active_keys: vec![Key::KEY_LEFTSHIFT],
+            timeout: Some(Duration::from_millis(300)),
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+    let mut switcher = LayerSwitcher::new(&layers);
+    switcher.start();
+
+    let clock = ManualClock::new();
+    let debouncer = Debouncer::new(DebounceMode::Eager, Duration::ZERO);
+    let mut pipeline = Pipeline::new(&clock, debouncer, switcher);
+    let mut emitted = Vec::new();
+
+    pipeline.report(EnumSet::from(XpPenButtons::XpB01));
+    pipeline.process_all(|e| emitted.push(e));
+    clock.advance(Duration::from_millis(10));
+    pipeline.report(EnumSet::empty());
+    pipeline.process_all(|e| emitted.push(e));
+    assert_eq!(emitted, vec![Emitted::Key(Key::KEY_LEFTSHIFT, true)]);
+
+    // The layer timeout wakes the pipeline while all keys are up
+    assert_eq!(pipeline.timeout(), Some(Duration::from_millis(290)));
+    clock.advance(Duration::from_millis(290));
+    pipeline.tick();
+    pipeline.process_all(|e| emitted.push(e));
+    assert_eq!(emitted[1..], [Emitted::Key(Key::KEY_LEFTSHIFT, false)]);
+    assert_eq!(pipeline.timeout(), None);
+}
+
+#[test]
+fn test_pipeline_reset() {
+    let layers = vec![
+        Layer {
+            keymap: vec![vec![vec![Lhold(1)]]],
+            ..DEFAULT_LAYER_CONFIG
+        },
+        Layer {
+            status_on_reset: crate::layout::types::LayerStatus::LayerPassthrough,
+            on_active_keys: vec![Key::KEY_LEFTCTRL],
+            ..DEFAULT_LAYER_CONFIG
+        },
+    ];
+    let mut switcher = LayerSwitcher::new(&layers);
+    switcher.start();
+
+    let clock = ManualClock::new();
+    let debouncer = Debouncer::new(DebounceMode::Eager, Duration::ZERO);
+    let mut pipeline = Pipeline::new(&clock, debouncer, switcher);
+    let mut queue = FrameQueue::new(Duration::ZERO);
+
+    pipeline.report(EnumSet::from(XpPenButtons::XpB01));
+    pipeline.process_all(|e| queue.push(e));
+    assert_eq!(pipeline.switcher().get_active_layers(), vec![0, 1]);
+    assert!(queue.is_down(Key::KEY_LEFTCTRL));
+
+    // The held key is pressed again after the reset
+    pipeline.reset();
+    assert_eq!(pipeline.switcher().get_active_layers(), vec![0]);
+    assert!(pipeline.next_input().is_none());
+    pipeline.report(EnumSet::from(XpPenButtons::XpB01));
+    assert!(pipeline.next_input() == Some(KeyStateChange::Pressed(XpPenButtons::XpB01)));
+}
+
+#[test]
+fn test_device_config() {
+    let mut config = DeviceConfig::default();
+    assert_eq!(config.name, "XP-Pen ACK05 driver");
+
+    config.set_id("28bd:0202").unwrap();
+    assert_eq!((config.vendor, config.product, config.version), (0x28bd, 0x0202, 0x111));
+    config.set_id("0x1209:0xAC05").unwrap();
+    assert_eq!((config.vendor, config.product), (0x1209, 0xac05));
+    config.set_id("1209:0001:0200").unwrap();
+    assert_eq!((config.vendor, config.product, config.version), (0x1209, 0x0001, 0x0200));
+
+    assert!(config.set_id("1209").is_err());
+    assert!(config.set_id("1209:xyz").is_err());
+    assert!(config.set_id("1:2:3:4").is_err());
+    assert!(config.set_id("10000:1").is_err());
+    assert_eq!((config.vendor, config.product, config.version), (0x1209, 0x0001, 0x0200));
+
+    assert_eq!(config.bus, evdev::BusType::BUS_USB);
+    config.set_bus("virtual").unwrap();
+    assert_eq!(config.bus, evdev::BusType::BUS_VIRTUAL);
+    config.set_bus("BUS_BLUETOOTH").unwrap();
+    assert_eq!(config.bus, evdev::BusType::BUS_BLUETOOTH);
+    assert_eq!(config.set_bus("serial port"), Err("Unknown bus type \"serial port\"".to_string()));
+}
+
+#[test]
+fn test_grab_siblings() {
+    use crate::grab::usb_parent;
+    use std::path::Path;
+
+    let usb = Path::new("/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2");
+    let hidraw = usb.join("1-2:1.1/0003:28BD:0202.0006");
+    let keyboard = usb.join("1-2:1.0/0003:28BD:0202.0005/input/input12");
+    assert_eq!(usb_parent(&hidraw), Some(usb));
+    assert!(is_sibling(&keyboard, usb));
+
+    // A second pad on another port, or behind a hub
+    let other = Path::new("/sys/devices/pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0/0003:28BD:0202.0007/input/input13");
+    assert!(!is_sibling(other, usb));
+    let hub = Path::new("/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.4/1-2.4:1.0/0003:28BD:0202.0008/input/input14");
+    assert_eq!(usb_parent(hub), Some(Path::new("/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.4")));
+    assert!(!is_sibling(hub, usb));
+
+    // The driver's own devices are virtual
+    assert!(!is_sibling(Path::new("/sys/devices/virtual/input/input20"), usb));
+    assert_eq!(usb_parent(Path::new("/sys/devices/virtual/input/input20")), None);
+}
+
+// Finds the evdev nodes of the pad in a fake sysfs tree laid out like the
+// real one: the class entries link to the devices below the USB interfaces
+#[test]
+fn test_grab_sysfs_discovery() {
+    use crate::grab::{hidraw_usb_device, sibling_nodes};
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+
+    let sysfs = std::env::temp_dir().join(format!("xppen-sysfs-{}", std::process::id()));
+    let usb1 = sysfs.join("devices/pci0000:00/0000:00:14.0/usb1");
+    let link = |class: &str, name: &str, device: &str| {
+        std::fs::create_dir_all(usb1.join(device)).unwrap();
+        let entry = sysfs.join("class").join(class).join(name);
+        std::fs::create_dir_all(&entry).unwrap();
+        symlink(usb1.join(device), entry.join("device")).unwrap();
+    };
+    link("hidraw", "hidraw3", "1-2/1-2:1.1/0003:28BD:0202.0006");
+    link("input", "event5", "1-2/1-2:1.0/0003:28BD:0202.0005/input/input12");
+    link("input", "event6", "1-2/1-2:1.1/0003:28BD:0202.0006/input/input13");
+    // Another pad on a hub behind the same port, and a virtual device
+    link("input", "event7", "1-2/1-2.4/1-2.4:1.0/0003:28BD:0202.0008/input/input14");
+    std::fs::create_dir_all(sysfs.join("devices/virtual/input/input20")).unwrap();
+    std::fs::create_dir_all(sysfs.join("class/input/event8")).unwrap();
+    symlink(sysfs.join("devices/virtual/input/input20"), sysfs.join("class/input/event8/device")).unwrap();
+
+    let parent = hidraw_usb_device(&sysfs, "/dev/hidraw3");
+    let usb = std::fs::canonicalize(usb1.join("1-2")).unwrap();
+    assert_eq!(parent.as_deref(), Some(usb.as_path()));
+    assert_eq!(hidraw_usb_device(&sysfs, "/dev/hidraw9"), None);
+
+    let nodes = (5..=9).map(|n| PathBuf::from(format!("/dev/input/event{}", n)));
+    let siblings = sibling_nodes(&sysfs, &usb, nodes);
+    std::fs::remove_dir_all(&sysfs).unwrap();
+    assert_eq!(siblings, vec![Path::new("/dev/input/event5"), Path::new("/dev/input/event6")]);
+}
+
+// Grabs a fake keyboard created by uinput, skipped when /dev/uinput
+// is not accessible
+#[test]
+fn test_grab_uinput_fake() {
+    use evdev::uinput::VirtualDeviceBuilder;
+    use evdev::{AttributeSet, Device};
+
+    let mut keys = AttributeSet::<Key>::new();
+    keys.insert(Key::KEY_A);
+    let fake = VirtualDeviceBuilder::new()
+        .and_then(|b| b.name("Fake ACK05 keyboard").with_keys(&keys))
+        .and_then(|b| b.build());
+    let Ok(mut fake) = fake else {
+        eprintln!("uinput is not available, skipping");
+        return;
+    };
+    let fake_node = fake.enumerate_dev_nodes_blocking().unwrap().next().unwrap().unwrap();
+
+    let grab = SiblingGrab::grab_nodes([fake_node.clone()]);
+    assert_eq!(grab.paths().collect::<Vec<_>>(), vec![&fake_node]);
+
+    // Nobody else can grab the node until the grab is released
+    let mut other = Device::open(&fake_node).unwrap();
+    assert!(other.grab().is_err());
+    drop(grab);
+    other.grab().unwrap();
+}
+
+#[test]
+fn test_access_errors() {
+    use crate::virtual_keyboard::uinput_error;
+    use crate::xppen_hid::{access_error, check_access, UDEV_HINT};
+    use std::io::{Error, ErrorKind};
+
+    let denied = Error::from(ErrorKind::PermissionDenied);
+    assert!(access_error("/dev/hidraw3", &denied).starts_with("Cannot open /dev/hidraw3: permission denied"));
+    assert!(access_error("/dev/hidraw3", &denied).ends_with(UDEV_HINT));
+    assert!(access_error("/dev/hidraw3", &Error::from(ErrorKind::NotFound)).contains("disconnected"));
+
+    assert!(uinput_error(&denied).contains("udev rule"));
+    assert!(uinput_error(&Error::from(ErrorKind::NotFound)).contains("modprobe uinput"));
+
+    let missing = check_access("/nonexistent/hidraw").unwrap_err();
+    assert_eq!(missing.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn test_signal_wakes_blocked_read() {
+    use crate::signals::{Signals, Wake};
+    use std::io::Write;
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    // A device that never reports anything
+    let (device, mut pad) = UnixStream::pair().unwrap();
+    let signals = Arc::new(Signals::new().unwrap());
+    let flag = Arc::new(AtomicBool::new(false));
+    signals.register(signal_hook::consts::SIGUSR1, &flag).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let waiter = {
+        let signals = Arc::clone(&signals);
+        std::thread::spawn(move || {
+            tx.send(signals.wait(device.as_fd(), None).unwrap()).unwrap();
+            tx.send(signals.wait(device.as_fd(), None).unwrap()).unwrap();
+        })
+    };
+
+    // The signal is handled by this thread, the wake up pipe ends the wait
+    std::thread::sleep(Duration::from_millis(50));
+    signal_hook::low_level::raise(signal_hook::consts::SIGUSR1).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Wake::Signal));
+    assert!(flag.load(Ordering::Relaxed));
+
+    // The wake up is consumed, the next wait sees the device again
+    pad.write_all(&[1]).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Wake::Readable));
+    waiter.join().unwrap();
+
+    let (device, _pad) = UnixStream::pair().unwrap();
+    assert_eq!(signals.wait(device.as_fd(), Some(Duration::from_millis(1))).unwrap(), Wake::Timeout);
+}
+
+#[test]
+fn test_interrupted_wait() {
+    use crate::signals::{Signals, Wake};
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{mpsc, Arc};
+
+    // A signal delivered to the waiting thread interrupts poll() even
+    // without the wake up pipe, that is a wake up and not an error
+    signal_hook::flag::register(signal_hook::consts::SIGUSR2, Arc::new(AtomicBool::new(false))).unwrap();
+    let (device, _pad) = UnixStream::pair().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let waiter = std::thread::spawn(move || {
+        let signals = Signals::new().unwrap();
+        tx.send(signals.wait(device.as_fd(), None).map_err(|e| e.to_string())).unwrap();
+    });
+
+    // Repeated until the signal hits the thread blocked in poll()
+    let woken = loop {
+        // SAFETY: the thread is joined only after it returned from the wait
+        unsafe { libc::pthread_kill(waiter.as_pthread_t(), signal_hook::consts::SIGUSR2) };
+        if let Ok(result) = rx.recv_timeout(Duration::from_millis(20)) {
+            break result;
+        }
+    };
+    assert_eq!(woken, Ok(Wake::Signal));
+    waiter.join().unwrap();
+}
+
+#[test]
+fn test_systemd_unit_signals() {
+    use crate::signals::{Signals, Wake};
+    use signal_hook::consts::{SIGHUP, SIGTERM};
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    // The signals are registered the same way as the device loop does
+    let signals = Arc::new(Signals::new().unwrap());
+    let terminate = Arc::new(AtomicBool::new(false));
+    let reset = Arc::new(AtomicBool::new(false));
+    signals.register(SIGTERM, &terminate).unwrap();
+    signals.register(SIGHUP, &reset).unwrap();
+
+    // The loop is blocked waiting for an idle pad
+    let (device, _pad) = UnixStream::pair().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let waiter = {
+        let signals = Arc::clone(&signals);
+        std::thread::spawn(move || {
+            for _ in 0..2 {
+                tx.send(signals.wait(device.as_fd(), None).unwrap()).unwrap();
+            }
+        })
+    };
+
+    // "systemctl reload" runs the ExecReload command of the unit
+    let unit = include_str!("../../systemd/xppen-ack05.service");
+    let reload = unit.lines().find_map(|l| l.strip_prefix("ExecReload=")).unwrap();
+    let reload = reload.replace("$MAINPID", &std::process::id().to_string());
+    let mut args = reload.split_whitespace();
+    let status = Command::new(args.next().unwrap()).args(args).status().unwrap();
+    assert!(status.success());
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Wake::Signal));
+    assert!(reset.load(Ordering::Relaxed));
+    assert!(!terminate.load(Ordering::Relaxed));
+
+    // "systemctl stop" sends SIGTERM, the unit does not override KillSignal
+    assert!(!unit.contains("KillSignal="));
+    signal_hook::low_level::raise(SIGTERM).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Wake::Signal));
+    assert!(terminate.load(Ordering::Relaxed));
+    waiter.join().unwrap();
+}
@@ -17,13 +17,13 @@ impl TestTime {
     }
 
     pub(super) fn advance_ms(&mut self, ms: u64) -> Instant {
-        self.t = self.t + Duration::from_millis(ms);
+        self.t += Duration::from_millis(ms);
         self.t
     }
 }
 
-impl Into<Instant> for TestTime {
-    fn into(self) -> Instant {
-        self.t
+impl From<TestTime> for Instant {
+    fn from(val: TestTime) -> Self {
+        val.t
     }
 }
\ No newline at end of file
@@ -0,0 +1,83 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+/// Components whose state changes with time report when they need to be
+/// ticked next. The main loop waits for input until the earliest deadline
+/// and ticks the components exactly when it is due, instead of polling.
+pub trait Deadline {
+    /// The earliest time the component needs a tick, None when it only
+    /// reacts to input
+    fn next_deadline(&self) -> Option<Instant>;
+}
+
+/// The earliest of the deadlines
+pub fn earliest<I>(deadlines: I) -> Option<Instant>
+where
+    I: IntoIterator<Item = Option<Instant>>,
+{
+    deadlines.into_iter().flatten().min()
+}
+
+/// Time left until the deadline, None waits forever. Already passed
+/// deadlines do not wait at all.
+pub fn timeout(deadline: Option<Instant>, now: Instant) -> Option<Duration> {
+    deadline.map(|d| d.saturating_duration_since(now))
+}
+
+/// Source of time for the input pipeline. The pipeline reads the clock once
+/// per device report or tick, so all components see the same time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock> Clock for &C {
+    fn now(&self) -> Instant {
+        (*self).now()
+    }
+}
+
+/// The monotonic system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Virtual time that only moves when told to, used by tests and
+/// the simulator to run a whole session without waiting
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Cell<Instant>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// Start the virtual time at the current system time
+    pub fn new() -> Self {
+        Self { now: Cell::new(Instant::now()) }
+    }
+
+    pub fn advance(&self, duration: Duration) -> Instant {
+        self.now.set(self.now.get() + duration);
+        self.now.get()
+    }
+
+    /// Move to a time, the clock never goes back
+    pub fn set(&self, t: Instant) {
+        self.now.set(self.now.get().max(t));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use evdev::Key;
+
+use crate::pipeline::Emitted;
+use crate::timer::Deadline;
+
+/// Default delay between two output frames
+pub const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(2);
+
+/// Keys that are pressed together with the next key of the same frame
+fn is_modifier(key: Key) -> bool {
+    matches!(
+        key,
+        Key::KEY_LEFTCTRL
+            | Key::KEY_RIGHTCTRL
+            | Key::KEY_LEFTSHIFT
+            | Key::KEY_RIGHTSHIFT
+            | Key::KEY_LEFTALT
+            | Key::KEY_RIGHTALT
+            | Key::KEY_LEFTMETA
+            | Key::KEY_RIGHTMETA
+    )
+}
+
+/// Groups the emitted keys into frames, each frame is sent with a single
+/// SYN_REPORT. A frame contains either modifier presses, or a single key
+/// press, or releases, so "Ctrl+Shift+A" becomes
+/// [Ctrl, Shift down] [A down] [A, Shift, Ctrl up].
+///
+/// The queue also tracks the keys it believes are down. A key pressed
+/// twice is only sent down once and up after the last release,
+/// releases of keys that are not down are dropped.
+pub struct FrameQueue {
+    /// Minimal delay between two frames
+    delay: Duration,
+    /// Key -> number of presses not released yet
+    down: HashMap<Key, u32>,
+    frames: VecDeque<Vec<Emitted>>,
+    /// The next frame must not be sent before this time
+    ready_at: Option<Instant>,
+}
+
+impl Default for FrameQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_FRAME_DELAY)
+    }
+}
+
+impl FrameQueue {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            down: HashMap::new(),
+            frames: VecDeque::new(),
+            ready_at: None,
+        }
+    }
+
+    pub fn get_delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Is the key down, including the frames that were not sent yet?
+    pub fn is_down(&self, key: Key) -> bool {
+        self.down.contains_key(&key)
+    }
+
+    /// Keys that are down, in no particular order
+    pub fn keys_down(&self) -> impl Iterator<Item = Key> + '_ {
+        self.down.keys().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Add an event to the last frame, or start a new one
+    pub fn push(&mut self, ev: Emitted) {
+        if let Emitted::Key(key, pressed) = ev {
+            let count = self.down.entry(key).or_insert(0);
+            let send = if pressed {
+                *count += 1;
+                *count == 1
+            } else if *count > 0 {
+                *count -= 1;
+                *count == 0
+            } else {
+                false
+            };
+            if *count == 0 {
+                self.down.remove(&key);
+            }
+            if !send {
+                return;
+            }
+        }
+
+        let fits = match self.frames.back() {
+            None => false,
+            Some(frame) => frame.iter().all(|prev| match (prev, &ev) {
+                (Emitted::Motion(a, _), Emitted::Motion(b, _)) => a != b,
+                (Emitted::Key(k0, true), Emitted::Key(k1, true)) => {
+                    is_modifier(*k0) && is_modifier(*k1) && k0 != k1
+                }
+                (Emitted::Key(k0, false), Emitted::Key(k1, false)) => k0 != k1,
+                _ => false,
+            }),
+        };
+
+        if fits {
+            self.frames.back_mut().unwrap().push(ev);
+        } else {
+            self.frames.push_back(vec![ev]);
+        }
+    }
+
+    /// Take the next frame when its time has come
+    pub fn next_frame(&mut self, now: Instant) -> Option<Vec<Emitted>> {
+        if self.ready_at.is_some_and(|t| now < t) {
+            return None;
+        }
+
+        let frame = self.frames.pop_front()?;
+        self.ready_at = Some(now + self.delay);
+        Some(frame)
+    }
+}
+
+impl Deadline for FrameQueue {
+    /// Time to send the next delayed frame
+    fn next_deadline(&self) -> Option<Instant> {
+        self.frames.front().and(self.ready_at)
+    }
+}
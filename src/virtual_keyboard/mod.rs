@@ -1,46 +1,394 @@
-use evdev::{AttributeSet, EventType, InputEvent, Key};
+pub mod frames;
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use evdev::{AttributeSet, BusType, EventType, InputEvent, InputId, Key, MiscType, RelativeAxisType};
 use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use log::info;
+
+use crate::pipeline::Emitted;
+use crate::timer::Deadline;
+use self::frames::FrameQueue;
+
+/// The uinput devices and the keys they sent down
+struct Devices {
+    kbd: VirtualDevice,
+    /// Mouse buttons and relative motion go through a separate device,
+    /// which is only created when the layout uses them
+    pointer: Option<VirtualDevice>,
+    /// Media and system control keys go through a consumer control device,
+    /// which is only created when the layout uses them
+    consumer: Option<VirtualDevice>,
+    /// Keys sent down and not released yet
+    down: HashSet<Key>,
+}
 
+/// Virtual keyboard, pointer and consumer control devices emitting the
+/// layout output. Keys that are down are released when the keyboard is
+/// dropped, on panic (see `release_on_panic`) and by `release_all`,
+/// so no modifier stays stuck after the daemon stops.
 pub struct VirtualKeyboard {
-    kbd: VirtualDevice
+    /// Shared with the panic hook
+    devices: Arc<Mutex<Devices>>,
+    /// Emitted events waiting to be sent, grouped into frames
+    queue: FrameQueue,
+    /// evdev nodes of all the virtual devices
+    nodes: Vec<PathBuf>,
+}
+
+/// HID consumer page (0x0C) usages of the supported media and system control keys
+const CONSUMER_USAGES: &[(Key, i32)] = &[
+    (Key::KEY_VOLUMEUP, 0x0c00e9),
+    (Key::KEY_VOLUMEDOWN, 0x0c00ea),
+    (Key::KEY_MUTE, 0x0c00e2),
+    (Key::KEY_PLAYPAUSE, 0x0c00cd),
+    (Key::KEY_NEXTSONG, 0x0c00b5),
+    (Key::KEY_PREVIOUSSONG, 0x0c00b6),
+    (Key::KEY_STOPCD, 0x0c00b7),
+    (Key::KEY_BRIGHTNESSUP, 0x0c006f),
+    (Key::KEY_BRIGHTNESSDOWN, 0x0c0070),
+];
+
+/// Keyboard key codes registered by `DeviceConfig::all_keys`,
+/// KEY_ESC up to the start of the button range
+const KEYBOARD_KEYS: std::ops::Range<u16> = 1..0x100;
+
+/// Identity of the virtual devices as seen by udev, libinput and compositors
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    /// Name of the keyboard, the pointer and the consumer control
+    /// devices get a suffix
+    pub name: String,
+    pub bus: BusType,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    /// Register all keyboard keys instead of the keys used by the layout,
+    /// so a different layout can be used without recreating the devices
+    pub all_keys: bool,
+}
+
+impl Default for DeviceConfig {
+    /// The identity used by the earlier versions
+    fn default() -> Self {
+        Self {
+            name: "XP-Pen ACK05 driver".to_string(),
+            bus: BusType::BUS_USB,
+            vendor: 0x1234,
+            product: 0x5678,
+            version: 0x111,
+            all_keys: false,
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Set the IDs from "vendor:product" or "vendor:product:version",
+    /// hexadecimal like in lsusb
+    pub fn set_id(&mut self, id: &str) -> Result<(), String> {
+        let parts = id
+            .split(':')
+            .map(|p| u16::from_str_radix(p.trim().trim_start_matches("0x"), 16))
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|_| format!("Invalid device ID \"{}\", expected vendor:product[:version]", id))?;
+
+        match parts.as_slice() {
+            [vendor, product] => (self.vendor, self.product) = (*vendor, *product),
+            [vendor, product, version] => {
+                (self.vendor, self.product, self.version) = (*vendor, *product, *version)
+            }
+            _ => return Err(format!("Invalid device ID \"{}\", expected vendor:product[:version]", id)),
+        }
+        Ok(())
+    }
+
+    /// Set the bus type by its name, like "usb", "bluetooth" or "virtual"
+    pub fn set_bus(&mut self, bus: &str) -> Result<(), String> {
+        let name = format!("BUS_{}", bus.trim().trim_start_matches("BUS_").to_uppercase());
+        self.bus = name.parse().map_err(|_| format!("Unknown bus type \"{}\"", bus))?;
+        Ok(())
+    }
+
+    fn input_id(&self) -> InputId {
+        InputId::new(self.bus, self.vendor, self.product, self.version)
+    }
+}
+
+/// Get the HID consumer usage of a media or system control key
+fn consumer_usage(key: Key) -> Option<i32> {
+    CONSUMER_USAGES
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, usage)| *usage)
+}
+
+/// Mouse buttons are routed to the virtual pointer instead of the keyboard
+fn is_pointer_button(key: Key) -> bool {
+    (Key::BTN_LEFT.code()..=Key::BTN_TASK.code()).contains(&key.code())
+}
+
+/// Device used to create the virtual devices
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Explain why the virtual devices cannot be created
+pub fn uinput_error(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => format!(
+            "{} is not writable, install the udev rule from the README, \
+             add your user to the input group and log in again",
+            UINPUT_PATH
+        ),
+        io::ErrorKind::NotFound => format!(
+            "{} does not exist, load the uinput kernel module (modprobe uinput)",
+            UINPUT_PATH
+        ),
+        _ => format!("Cannot open {}: {}", UINPUT_PATH, e),
+    }
+}
+
+/// Can the virtual devices be created? The error tells what to fix.
+pub fn check_uinput() -> Result<(), String> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(UINPUT_PATH)
+        .map(|_| ())
+        .map_err(|e| uinput_error(&e))
 }
 
 impl VirtualKeyboard {
-    pub fn new<I>(keyset: I) -> Self
+    /// Create the virtual keyboard, and the pointer and consumer control
+    /// devices when the keys need them. The error tells what to fix.
+    pub fn new<I, A>(config: &DeviceConfig, keyset: I, axes: A) -> Result<Self, String>
     where
-        I: IntoIterator<Item=Key>
+        I: IntoIterator<Item=Key>,
+        A: IntoIterator<Item=RelativeAxisType>,
     {
         let mut keys = AttributeSet::<Key>::new();
-        for k in keyset {
-            keys.insert(k);
+        let mut buttons = AttributeSet::<Key>::new();
+        let mut media = AttributeSet::<Key>::new();
+        let all_keys = KEYBOARD_KEYS.map(Key::new).filter(|_| config.all_keys);
+        for k in keyset.into_iter().chain(all_keys) {
+            if is_pointer_button(k) {
+                buttons.insert(k);
+            } else if consumer_usage(k).is_some() {
+                media.insert(k);
+            } else {
+                keys.insert(k);
+            }
+        }
+
+        let mut rel = AttributeSet::<RelativeAxisType>::new();
+        for a in axes {
+            rel.insert(a);
         }
 
-        let mut kbd = VirtualDeviceBuilder::new().unwrap()
-            .name("XP-Pen ACK05 driver")
-            .with_keys(&keys).unwrap()
+        let error = |e: io::Error| format!("Cannot create the virtual device: {}", e);
+        let mut nodes = Vec::new();
+
+        let mut kbd = VirtualDeviceBuilder::new().map_err(|e| uinput_error(&e))?
+            .name(&config.name)
+            .input_id(config.input_id())
+            .with_keys(&keys).map_err(error)?
             .build()
-            .unwrap();
+            .map_err(error)?;
 
-        for path in kbd.enumerate_dev_nodes_blocking().unwrap() {
-            let path = path.unwrap();
-            println!("Available as {}", path.display());
+        for path in kbd.enumerate_dev_nodes_blocking().map_err(error)? {
+            let path = path.map_err(error)?;
+            info!("Available as {}", path.display());
+            nodes.push(path);
         }
 
-        Self {
-            kbd
+        let pointer = if buttons.iter().next().is_some() || rel.iter().next().is_some() {
+            // Pointer devices are only recognized when they have X and Y axes
+            // and the basic set of buttons
+            rel.insert(RelativeAxisType::REL_X);
+            rel.insert(RelativeAxisType::REL_Y);
+            buttons.insert(Key::BTN_LEFT);
+            buttons.insert(Key::BTN_RIGHT);
+            buttons.insert(Key::BTN_MIDDLE);
+
+            let mut pointer = VirtualDeviceBuilder::new().map_err(|e| uinput_error(&e))?
+                .name(&format!("{} pointer", config.name))
+                .input_id(config.input_id())
+                .with_keys(&buttons).map_err(error)?
+                .with_relative_axes(&rel).map_err(error)?
+                .build()
+                .map_err(error)?;
+
+            for path in pointer.enumerate_dev_nodes_blocking().map_err(error)? {
+                let path = path.map_err(error)?;
+                info!("Pointer available as {}", path.display());
+                nodes.push(path);
+            }
+
+            Some(pointer)
+        } else {
+            None
+        };
+
+        let consumer = if media.iter().next().is_some() {
+            let mut msc = AttributeSet::<MiscType>::new();
+            msc.insert(MiscType::MSC_SCAN);
+
+            let mut consumer = VirtualDeviceBuilder::new().map_err(|e| uinput_error(&e))?
+                .name(&format!("{} consumer control", config.name))
+                .input_id(config.input_id())
+                .with_keys(&media).map_err(error)?
+                .with_msc(&msc).map_err(error)?
+                .build()
+                .map_err(error)?;
+
+            for path in consumer.enumerate_dev_nodes_blocking().map_err(error)? {
+                let path = path.map_err(error)?;
+                info!("Consumer control available as {}", path.display());
+                nodes.push(path);
+            }
+
+            Some(consumer)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            devices: Arc::new(Mutex::new(Devices {
+                kbd,
+                pointer,
+                consumer,
+                down: HashSet::new(),
+            })),
+            queue: FrameQueue::default(),
+            nodes,
+        })
+    }
+
+    /// evdev nodes of the virtual devices
+    pub fn nodes(&self) -> &[PathBuf] {
+        &self.nodes
+    }
+
+    /// Lock the devices, a panic while they were locked does not
+    /// prevent the release of the keys
+    fn devices(&self) -> MutexGuard<'_, Devices> {
+        self.devices.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Set the minimal delay between two frames, some applications miss
+    /// shortcuts when the modifiers arrive together with the key
+    pub fn set_frame_delay(&mut self, delay: Duration) {
+        self.queue = FrameQueue::new(delay);
+    }
+
+    /// Queue an emitted key or motion, `flush` sends it
+    pub fn push(&mut self, ev: Emitted) {
+        self.queue.push(ev);
+    }
+
+    /// Send the frames that are due, the rest waits for the deadline.
+    /// Stops at the first frame that cannot be written.
+    pub fn flush(&mut self, now: Instant) -> io::Result<()> {
+        while let Some(frame) = self.queue.next_frame(now) {
+            self.devices().emit_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the frames waiting to be sent and release all keys that are down
+    /// immediately. Use it when the layout state is reset.
+    pub fn release_all(&mut self) {
+        self.queue = FrameQueue::new(self.queue.get_delay());
+        self.devices().release_all();
+    }
+
+    /// Release the keys that are down when the program panics, before
+    /// the panic message is printed. Installed once for the keyboard.
+    pub fn release_on_panic(&self) {
+        let devices = Arc::downgrade(&self.devices);
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // The devices may be locked by the panicking code,
+            // they get released on drop then
+            if let Some(Ok(mut devices)) = devices.upgrade().as_ref().map(|d| d.try_lock()) {
+                devices.release_all();
+            }
+            hook(info);
+        }));
+    }
+}
+
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        self.devices().release_all();
+    }
+}
+
+impl Devices {
+    /// Release all keys that are down, each device with a single SYN_REPORT
+    fn release_all(&mut self) {
+        let mut down: Vec<Key> = self.down.iter().copied().collect();
+        down.sort_by_key(|k| k.code());
+        let frame: Vec<Emitted> = down.into_iter().map(|k| Emitted::Key(k, false)).collect();
+        if !frame.is_empty() {
+            info!("Releasing {} keys that are still down", frame.len());
+            // Errors are ignored, this also runs while panicking
+            let _ = self.emit_frame(&frame);
         }
     }
 
-    pub fn emit_key(&mut self, key: Key, down: bool) {
-        let code = key.code();
-        let type_ = EventType::KEY;
+    /// Send a frame, each device gets its part with a single SYN_REPORT
+    fn emit_frame(&mut self, frame: &[Emitted]) -> io::Result<()> {
+        let mut kbd = Vec::new();
+        let mut pointer = Vec::new();
+        let mut consumer = Vec::new();
 
-        if down {
-            let down_event = InputEvent::new(type_, code, 1);
-            self.kbd.emit(&[down_event]).unwrap();
-        } else {
-            let down_event = InputEvent::new(type_, code, 0);
-            self.kbd.emit(&[down_event]).unwrap();
+        for ev in frame {
+            match *ev {
+                Emitted::Key(key, down) => {
+                    if down {
+                        self.down.insert(key);
+                    } else {
+                        self.down.remove(&key);
+                    }
+
+                    let key_event = InputEvent::new(EventType::KEY, key.code(), down as i32);
+                    match consumer_usage(key) {
+                        // Report the consumer usage the same way a real HID device does
+                        Some(usage) if self.consumer.is_some() => {
+                            consumer.push(InputEvent::new(EventType::MISC, MiscType::MSC_SCAN.0, usage));
+                            consumer.push(key_event);
+                        }
+                        _ if self.pointer.is_some() && is_pointer_button(key) => pointer.push(key_event),
+                        _ => kbd.push(key_event),
+                    }
+                }
+                Emitted::Motion(axis, value) => {
+                    pointer.push(InputEvent::new(EventType::RELATIVE, axis.0, value));
+                }
+            }
+        }
+
+        // VirtualDevice::emit terminates the batch with SYN_REPORT
+        if !kbd.is_empty() {
+            self.kbd.emit(&kbd)?;
+        }
+        if let (Some(dev), false) = (&mut self.pointer, pointer.is_empty()) {
+            dev.emit(&pointer)?;
         }
+        if let (Some(dev), false) = (&mut self.consumer, consumer.is_empty()) {
+            dev.emit(&consumer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deadline for VirtualKeyboard {
+    fn next_deadline(&self) -> Option<Instant> {
+        self.queue.next_deadline()
     }
 }
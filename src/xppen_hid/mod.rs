@@ -1,15 +1,30 @@
 use enumset::{EnumSet, EnumSetType};
-use hidapi::{self, BusType, HidApi, HidDevice, HidResult};
+use hidapi::{BusType, DeviceInfo, HidApi, HidDevice};
+use log::{debug, info};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use crate::grab;
 use crate::kbd_events::HasState;
+use crate::signals::{Signals, Wake};
 use crate::layout::types::KeyCoords;
 
-const PID: u16 = 0x0202;
-const VID: u16 = 0x28bd;
+pub const PID: u16 = 0x0202;
+pub const VID: u16 = 0x28bd;
+
+/// Usage of the vendor interface reporting the buttons
+const KEYS_USAGE_PAGE: u16 = 0xff0a;
+const KEYS_USAGE: u16 = 0x1;
 
 // XP-Pen ACK05
 pub struct XpPenAck05 {
-    device: HidDevice,
+    /// The keys interface, read directly so the wait can be woken up by signals
+    file: File,
+    /// hidraw node of the keys interface
+    path: String,
 }
 
 #[derive(EnumSetType, Debug, Hash)]
@@ -28,9 +43,9 @@ pub enum XpPenButtons {
     XpRoCCW,
 }
 
-impl Into<KeyCoords> for XpPenButtons {
-    fn into(self) -> KeyCoords {
-        return KeyCoords(0, 0, self as u8);
+impl From<XpPenButtons> for KeyCoords {
+    fn from(val: XpPenButtons) -> Self {
+        KeyCoords(0, 0, val as u8)
     }
 }
 
@@ -38,70 +53,147 @@ impl HasState for XpPenButtons {
     // Rotary encoder has no state, all the other buttons can be up or down
     // Stateless buttons emit a pressed event every time they appear in the pressed report
     fn has_state(self) -> bool {
-        return !(self == XpPenButtons::XpRoCCW || self == XpPenButtons::XpRoCW);
+        !(self == XpPenButtons::XpRoCCW || self == XpPenButtons::XpRoCW)
+    }
+}
+
+/// What to do when the pad's hidraw node cannot be opened
+pub const UDEV_HINT: &str = "install the udev rule from the README \
+    (/etc/udev/rules.d/90-xppen-ack05.rules), add your user to the input group \
+    and reconnect the pad";
+
+/// HID interfaces of the connected pads
+pub fn interfaces(api: &HidApi) -> impl Iterator<Item = &DeviceInfo> {
+    api.device_list()
+        .filter(|device| device.vendor_id() == VID && device.product_id() == PID)
+}
+
+/// Is the interface the vendor one, which reports the buttons
+/// in the key bit mode?
+pub fn is_keys_interface(device: &DeviceInfo) -> bool {
+    device.usage_page() == KEYS_USAGE_PAGE && device.usage() == KEYS_USAGE
+}
+
+/// Can the hidraw node be opened for reading and writing?
+pub fn check_access(path: &str) -> io::Result<()> {
+    OpenOptions::new().read(true).write(true).open(path).map(|_| ())
+}
+
+/// Explain why a hidraw node cannot be opened
+pub fn access_error(path: &str, e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            format!("Cannot open {}: permission denied, {}", path, UDEV_HINT)
+        }
+        io::ErrorKind::NotFound => format!("Cannot open {}: the pad was disconnected", path),
+        _ => format!("Cannot open {}: {}", path, e),
+    }
+}
+
+/// A HID interface of a connected pad
+#[derive(Debug)]
+pub struct PadInterface {
+    /// hidraw node, like /dev/hidraw3
+    pub path: String,
+    pub interface: i32,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub bus: BusType,
+    pub serial: String,
+    /// sysfs path of the USB device, shared by all interfaces of a pad
+    pub usb_device: Option<PathBuf>,
+    /// Result of opening the node for reading and writing
+    pub access: io::Result<()>,
+}
+
+impl PadInterface {
+    pub fn is_keys(&self) -> bool {
+        self.usage_page == KEYS_USAGE_PAGE && self.usage == KEYS_USAGE
     }
 }
 
-fn open_keyboard(api: &HidApi) -> Option<HidDevice> {
-    for device in api.device_list() {
-        if device.vendor_id() == VID
-            && device.product_id() == PID
-            && device.usage_page() == 0xff0a
-            && device.usage() == 0x1
-        {
-            println!(
-                "SELECTING {:?} {:?} {:?} {:?} interface: {} usage: {:04x} ({:04x})",
-                device.path(),
-                device.manufacturer_string(),
-                device.product_string(),
-                device.serial_number(),
-                device.interface_number(),
-                device.usage(),
-                device.usage_page()
-            );
-            if let HidResult::Ok(hid) = device.open_device(api) {
-                return Some(hid);
+/// All HID interfaces of the connected pads, in the order of enumeration
+pub fn list() -> Result<Vec<PadInterface>, String> {
+    let api = HidApi::new().map_err(|e| format!("Cannot enumerate HID devices: {}", e))?;
+
+    Ok(interfaces(&api)
+        .map(|device| {
+            let path = device.path().to_string_lossy().into_owned();
+            PadInterface {
+                access: check_access(&path),
+                usb_device: grab::hidraw_usb_device(Path::new(grab::SYSFS), &path),
+                path,
+                interface: device.interface_number(),
+                usage_page: device.usage_page(),
+                usage: device.usage(),
+                bus: device.bus_type(),
+                serial: device.serial_number().unwrap_or("").to_string(),
+            }
+        })
+        .collect())
+}
+
+/// Open the keys interface of the first accessible pad, or of the pad whose
+/// keys interface is at the given hidraw path. The error tells what to fix.
+fn open_keyboard(api: &HidApi, path: Option<&str>) -> Result<(HidDevice, String), String> {
+    let mut error = None;
+
+    for device in interfaces(api).filter(|d| is_keys_interface(d)) {
+        let device_path = device.path().to_string_lossy();
+        if path.is_some_and(|p| p != device_path) {
+            continue;
+        }
+
+        if let Err(e) = check_access(&device_path) {
+            error.get_or_insert(access_error(&device_path, &e));
+            continue;
+        }
+
+        info!(
+            "Using {} {:?} {:?} {:?} interface: {} usage: {:04x} ({:04x})",
+            device_path,
+            device.manufacturer_string(),
+            device.product_string(),
+            device.serial_number(),
+            device.interface_number(),
+            device.usage(),
+            device.usage_page()
+        );
+        match device.open_device(api) {
+            Ok(hid) => return Ok((hid, device_path.into_owned())),
+            Err(e) => {
+                error.get_or_insert(format!("Cannot open {}: {}", device_path, e));
             }
         }
     }
 
-    println!("No device found.");
-    None
+    Err(error.unwrap_or_else(|| match path {
+        Some(p) => format!(
+            "{} is not the keys interface of an XP-Pen ACK05, list-devices shows the interfaces",
+            p
+        ),
+        None => "No XP-Pen ACK05 found, check that it is connected using USB".to_string(),
+    }))
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum XpPenResult {
     Timeout,
     TryAgain,
     Keys(EnumSet<XpPenButtons>),
+    /// A signal arrived while waiting
+    Signal,
+    /// The read failed, like when the pad is unplugged
+    Error(String),
 }
 
 impl XpPenAck05 {
-    pub fn new() -> Self {
-        let api = hidapi::HidApi::new().unwrap();
-
-        // Print out information about all connected devices
-        for device in api.device_list() {
-            println!(
-                "0x{:04x}:0x{:04x} 0x{:04x}:0x{:04x} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
-                device.vendor_id(),
-                device.product_id(),
-                device.usage(),
-                device.usage_page(),
-                device,
-                device.manufacturer_string(),
-                device.product_string(),
-                device.serial_number(),
-                device.interface_number(),
-                device.bus_type(),
-                device.release_number(),
-                device.path()
-            );
-        }
+    /// Open the first pad, or the one at the given hidraw path
+    pub fn open(path: Option<&str>) -> Result<Self, String> {
+        let api = HidApi::new().map_err(|e| format!("Cannot enumerate HID devices: {}", e))?;
 
         // Connect to device using its VID and PID
-        let device = open_keyboard(&api).unwrap();
-        println!("Device: {:?}", device);
+        let (device, path) = open_keyboard(&api, path)?;
 
         // Initialize XP-Pen ACK05
         // This was sniffed from the USB communication between the official application
@@ -111,31 +203,43 @@ impl XpPenAck05 {
             .get_device_info()
             .map_or(BusType::Usb, |info| info.bus_type());
         if let BusType::Usb = bus {
-            println!("Configuring USB HID key bit mode.");
+            debug!("Configuring USB HID key bit mode.");
             let buf = [0x02, 0xb0, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-            let res = device.write(&buf).unwrap();
-            println!("Wrote: {:?} byte(s)", res);
+            let res = device
+                .write(&buf)
+                .map_err(|e| format!("Cannot switch the pad to the key bit mode: {}", e))?;
+            debug!("Wrote: {:?} byte(s)", res);
         } else if let BusType::Bluetooth = bus {
-            println!("Configuring Bluetooth HID key bit mode.");
-            panic!("Bluetooth connection is currently not supported!.");
-            //let buf = [0x02, 0xb0, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-            //let res = device.write(&buf).unwrap();
-            //println!("Wrote: {:?} byte(s)", res);
+            debug!("Configuring Bluetooth HID key bit mode.");
+            return Err("Bluetooth connection is currently not supported, connect the pad using USB".to_string());
         }
 
-        Self { device }
+        let file = File::open(&path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        Ok(Self { file, path })
     }
 
-    pub fn set_blocking(&self) {
-        let _ = self.device.set_blocking_mode(true);
+    /// hidraw node of the opened keys interface
+    pub fn path(&self) -> &str {
+        &self.path
     }
 
-    pub fn read(&self, block: bool) -> XpPenResult {
+    /// Wait for a report until the timeout elapses or a signal arrives,
+    /// None waits forever (see `Signals::wait`)
+    pub fn read(&self, timeout: Option<Duration>, signals: &Signals) -> XpPenResult {
         let mut buf = [0u8; 32];
 
-        let timeout = if block { -1 } else { 25 };
+        match signals.wait(self.file.as_fd(), timeout) {
+            Ok(Wake::Readable) => {}
+            Ok(Wake::Signal) => return XpPenResult::Signal,
+            Ok(Wake::Timeout) => return XpPenResult::Timeout,
+            Err(e) => return XpPenResult::Error(e.to_string()),
+        }
 
-        let res = self.device.read_timeout(&mut buf[..], timeout).unwrap();
+        let res = match (&self.file).read(&mut buf[..]) {
+            Ok(res) => res,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return XpPenResult::Signal,
+            Err(e) => return XpPenResult::Error(e.to_string()),
+        };
         //println!("Read: {:?}", &buf[..res]);
         if res == 0 {
             return XpPenResult::Timeout;
@@ -184,6 +288,6 @@ impl XpPenAck05 {
             state |= XpPenButtons::XpRoCCW;
         }
 
-        return XpPenResult::Keys(state);
+        XpPenResult::Keys(state)
     }
 }